@@ -0,0 +1,26 @@
+use blockchain_p2p::sim::{simulate, Partition, SimConfig, SimNodeConfig};
+
+// Compares a healthy network with one that gets split in half for ten minutes
+fn main() {
+    let healthy = SimConfig {
+        nodes: vec![
+            SimNodeConfig { hashrate: 400. },
+            SimNodeConfig { hashrate: 200. },
+            SimNodeConfig { hashrate: 100. },
+            SimNodeConfig { hashrate: 100. },
+        ],
+        ..Default::default()
+    };
+    println!("Healthy network\n{}\n", simulate(healthy.clone()));
+
+    let partitioned = SimConfig {
+        partitions: vec![Partition {
+            start: 1200,
+            end: 1800,
+            isolated: vec![0, 2],
+        }],
+        loss: 0.05,
+        ..healthy
+    };
+    println!("Partitioned network\n{}", simulate(partitioned));
+}
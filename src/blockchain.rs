@@ -1,16 +1,43 @@
 use super::*;
+use crate::clock::{self, Clock};
 use crate::mining::calculate_dif_offset;
 use crypto_hash::{digest, Algorithm};
 use ed25519_dalek::PUBLIC_KEY_LENGTH;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use tracing::{debug_span, trace};
 
 #[derive(Clone, Serialize, Deserialize, Default)]
-pub struct Block {
-    pub transactions: Vec<Transaction>,
+pub struct BlockHeader {
+    pub prev_hash: Hash,
+    pub transactions_root: Hash,
     pub nonce: u64,
     pub timestamp: u64,
-    mined_by: PublicKey,
-    prev_hash: Hash,
+    pub mined_by: PublicKey,
+}
+
+impl core::fmt::Debug for BlockHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Header {:x} at {}, mined by {}",
+            self.hash(),
+            self.timestamp,
+            hex::encode(self.mined_by)
+        )
+    }
+}
+
+impl BlockHeader {
+    pub fn hash(&self) -> Hash {
+        let header_binary = bincode::serialize(&self).unwrap();
+        digest(Algorithm::SHA256, &header_binary)[0..32].into()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub transactions: Vec<Transaction>,
 }
 
 impl core::fmt::Debug for Block {
@@ -19,15 +46,22 @@ impl core::fmt::Debug for Block {
             f,
             "Block with {} transactions, mined by {}",
             self.transactions.len(),
-            hex::encode(self.mined_by)
+            hex::encode(self.header.mined_by)
         )
     }
 }
 
 impl Block {
     pub fn hash(&self) -> Hash {
-        let block_binary = bincode::serialize(&self).unwrap();
-        digest(Algorithm::SHA256, &block_binary)[0..32].into()
+        self.header.hash()
+    }
+    pub fn transactions_root(transactions: &[Transaction]) -> Hash {
+        let transactions_binary = bincode::serialize(transactions).unwrap();
+        digest(Algorithm::SHA256, &transactions_binary)[0..32].into()
+    }
+    pub fn add_transaction(&mut self, transaction: Transaction) {
+        self.transactions.push(transaction);
+        self.header.transactions_root = Block::transactions_root(&self.transactions);
     }
     pub fn spendings(&self, user: &PublicKey) -> u64 {
         self.transactions
@@ -38,21 +72,141 @@ impl Block {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BlockValidationError {
     PrevHashMismatch,
     NotMinedCorrectly,
     ExcessiveTransactionAmount,
     InvalidTransactionSignature,
     InvalidTimestamp,
+    FutureTimestamp,
+    TransactionsRootMismatch,
+    GenesisMismatch,
+    DuplicateTransaction,
 }
 
-#[derive(Clone, Serialize, Deserialize, Default)]
+fn next_difficulty(last: Option<&BlockHeader>, cur_dif: u32, header: &BlockHeader) -> u32 {
+    if let Some(lheader) = last {
+        let dif_offset = calculate_dif_offset(header.timestamp.saturating_sub(lheader.timestamp));
+        let answer = (cur_dif as i32 + dif_offset).max(0);
+        answer as u32
+    } else {
+        0
+    }
+}
+
+/// Median timestamp of the last `MEDIAN_TIME_SPAN` headers, given the most recent first.
+fn median_time_past<'a>(headers: impl Iterator<Item = &'a BlockHeader>) -> Option<u64> {
+    let mut timestamps: Vec<u64> = headers
+        .take(MEDIAN_TIME_SPAN)
+        .map(|h| h.timestamp)
+        .collect();
+    timestamps.sort_unstable();
+    timestamps.get(timestamps.len() / 2).copied()
+}
+
+/// Checks the link to the previous header, the timestamp and the proof of work,
+/// returning the difficulty the header was mined at. The timestamp has to be later than
+/// `median_time_past` and no later than `latest`.
+fn check_header(
+    last: Option<&BlockHeader>,
+    median_time_past: Option<u64>,
+    cur_dif: u32,
+    header: &BlockHeader,
+    latest: u64,
+) -> Result<u32, BlockValidationError> {
+    if let Some(lheader) = last {
+        if lheader.hash() != header.prev_hash {
+            return Err(BlockValidationError::PrevHashMismatch);
+        }
+        if median_time_past.is_some_and(|median| header.timestamp <= median) {
+            return Err(BlockValidationError::InvalidTimestamp);
+        }
+        if header.timestamp > latest {
+            return Err(BlockValidationError::FutureTimestamp);
+        }
+    }
+
+    let difficulty = next_difficulty(last, cur_dif, header);
+
+    if !mining::mined(header, difficulty) {
+        return Err(BlockValidationError::NotMinedCorrectly);
+    }
+
+    Ok(difficulty)
+}
+
+/// Chain of headers without transactions, used to compare the work of a peer's
+/// chain before downloading its blocks.
+#[derive(Clone, Debug, Default)]
+pub struct HeaderChain {
+    pub headers: Vec<BlockHeader>,
+    pub cur_dif: u32,
+    pub weight: u32,
+}
+
+impl HeaderChain {
+    /// Checks `headers` in order, none of them may be stamped later than `latest`.
+    pub fn construct(headers: Vec<BlockHeader>, latest: u64) -> Result<Self, BlockValidationError> {
+        let mut result = Self::default();
+        for header in headers {
+            result.add_header(header, latest)?;
+        }
+        Ok(result)
+    }
+
+    pub fn add_header(
+        &mut self,
+        header: BlockHeader,
+        latest: u64,
+    ) -> Result<(), BlockValidationError> {
+        let new_difficulty = check_header(
+            self.headers.last(),
+            median_time_past(self.headers.iter().rev()),
+            self.cur_dif,
+            &header,
+            latest,
+        )?;
+
+        self.weight += new_difficulty;
+        self.cur_dif = new_difficulty;
+        self.headers.push(header);
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Blockchain {
     pub blocks: Vec<Block>,
     pub balances: HashMap<[u8; PUBLIC_KEY_LENGTH], u64>,
     pub cur_dif: u32,
     pub weight: u32,
+    // every transaction in the chain, so none can be replayed, a tip keeps all of them
+    confirmed: HashSet<Hash>,
+    // stamps generated blocks and bounds the timestamps of added ones
+    #[serde(skip, default = "clock::system")]
+    clock: Arc<dyn Clock>,
+    #[serde(skip, default = "max_future_drift")]
+    max_future_drift: u64,
+}
+
+fn max_future_drift() -> u64 {
+    MAX_FUTURE_DRIFT
+}
+
+impl Default for Blockchain {
+    fn default() -> Self {
+        Self {
+            blocks: vec![],
+            balances: HashMap::new(),
+            cur_dif: 0,
+            weight: 0,
+            confirmed: HashSet::new(),
+            clock: clock::system(),
+            max_future_drift: MAX_FUTURE_DRIFT,
+        }
+    }
 }
 
 impl core::fmt::Debug for Blockchain {
@@ -69,89 +223,233 @@ impl core::fmt::Debug for Blockchain {
     }
 }
 
+/// Identifies a network, nodes only talk to peers with the same chain ID and genesis block.
+#[derive(Debug, Clone)]
+pub struct ChainSpec {
+    pub chain_id: String,
+    // how far ahead of the local clock block timestamps are accepted, in seconds
+    pub max_future_drift: u64,
+}
+
+impl Default for ChainSpec {
+    fn default() -> Self {
+        Self::new("mainnet")
+    }
+}
+
+impl ChainSpec {
+    pub fn new(chain_id: &str) -> Self {
+        Self {
+            chain_id: chain_id.to_string(),
+            max_future_drift: MAX_FUTURE_DRIFT,
+        }
+    }
+
+    pub fn genesis(&self) -> Block {
+        let mut genesis = Block {
+            header: BlockHeader {
+                // the first block has nothing to point to, so it commits to the chain ID instead
+                prev_hash: digest(Algorithm::SHA256, self.chain_id.as_bytes())[0..32].into(),
+                transactions_root: Block::transactions_root(&[]),
+                nonce: 0,
+                timestamp: GENESIS_TIMESTAMP,
+                mined_by: PublicKey::default(),
+            },
+            transactions: vec![],
+        };
+        while !mining::mined(&genesis.header, 0) {
+            genesis.header.nonce += 1;
+        }
+        genesis
+    }
+
+    pub fn genesis_hash(&self) -> Hash {
+        self.genesis().hash()
+    }
+}
+
 impl Blockchain {
-    pub fn construct(blocks: Vec<Block>) -> Result<Self, BlockValidationError> {
-        let mut result = Self::default();
+    pub fn new(chain: &ChainSpec, clock: Arc<dyn Clock>) -> Self {
+        let mut blockchain =
+            Self::construct(vec![chain.genesis()], clock).expect("genesis block is valid");
+        blockchain.max_future_drift = chain.max_future_drift;
+        blockchain
+    }
+
+    pub fn genesis_hash(&self) -> Option<Hash> {
+        self.blocks.first().map(|b| b.hash())
+    }
+
+    pub fn construct(
+        blocks: Vec<Block>,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self, BlockValidationError> {
+        let mut result = Self {
+            clock,
+            ..Self::default()
+        };
         for block in blocks {
             result.add_block(block)?;
         }
         Ok(result)
     }
 
-    pub fn difficulty(&self, block: &Block) -> u32 {
-        if let Some(lblock) = self.blocks.last() {
-            let dif_offset = calculate_dif_offset(block.timestamp - lblock.timestamp);
-            let answer = (self.cur_dif as i32 + dif_offset).max(0);
-            answer as u32
-        } else {
-            0
+    /// Constructs another chain out of `blocks`, checking them against the same clock and drift.
+    pub fn rebuild(&self, blocks: Vec<Block>) -> Result<Self, BlockValidationError> {
+        let mut result = self.empty();
+        for block in blocks {
+            result.add_block(block)?;
+        }
+        Ok(result)
+    }
+
+    /// Rebuilds a chain received from a peer, which has to start at our genesis block.
+    pub fn validate_chain(&self, blocks: Vec<Block>) -> Result<Self, BlockValidationError> {
+        if blocks.first().map(|b| b.hash()) != self.genesis_hash() {
+            return Err(BlockValidationError::GenesisMismatch);
         }
+        self.rebuild(blocks)
+    }
+
+    /// Fork choice, whether `other` should replace this chain.
+    /// The heavier chain wins, on a tie we keep ours.
+    pub fn prefers(&self, other: &Blockchain) -> bool {
+        other.weight > self.weight
+    }
+
+    /// Only the genesis block, to validate other chains on without holding on to this one.
+    pub fn genesis_chain(&self) -> Self {
+        self.rebuild(self.blocks.iter().take(1).cloned().collect())
+            .expect("genesis block is valid")
+    }
+
+    fn empty(&self) -> Self {
+        Self {
+            clock: self.clock.clone(),
+            max_future_drift: self.max_future_drift,
+            ..Self::default()
+        }
+    }
+
+    /// The last blocks and the balances, all the next block is checked against.
+    /// Blocks added to it can then be connected here with `connect_tip`.
+    pub fn tip(&self) -> Self {
+        Self {
+            blocks: self.blocks[self.blocks.len().saturating_sub(MEDIAN_TIME_SPAN)..].to_vec(),
+            balances: self.balances.clone(),
+            cur_dif: self.cur_dif,
+            weight: self.weight,
+            confirmed: self.confirmed.clone(),
+            ..self.empty()
+        }
+    }
+
+    /// Connects the block last added to `tip`, returning false when the chain has
+    /// moved on since the tip was taken.
+    pub fn connect_tip(&mut self, mut tip: Blockchain) -> bool {
+        let Some(block) = tip.blocks.pop() else {
+            return false;
+        };
+        if tip.blocks.last().map(|b| b.hash()) != self.blocks.last().map(|b| b.hash()) {
+            return false;
+        }
+        self.balances = tip.balances;
+        self.confirmed = tip.confirmed;
+        self.cur_dif = tip.cur_dif;
+        self.weight = tip.weight;
+        self.blocks.push(block);
+        true
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// The latest timestamp a new block may have.
+    pub fn latest_timestamp(&self) -> u64 {
+        self.clock.now() + self.max_future_drift
+    }
+
+    /// The earliest timestamp a new block may have, one second past the median time of the chain.
+    pub fn earliest_timestamp(&self) -> u64 {
+        median_time_past(self.blocks.iter().rev().map(|b| &b.header)).map_or(0, |median| median + 1)
+    }
+
+    pub fn difficulty(&self, header: &BlockHeader) -> u32 {
+        next_difficulty(self.blocks.last().map(|b| &b.header), self.cur_dif, header)
+    }
+
+    pub fn headers(&self) -> Vec<BlockHeader> {
+        self.blocks.iter().map(|b| b.header.clone()).collect()
     }
 
     pub fn generate_block(&self, mined_by: PublicKey) -> Block {
         Block {
-            nonce: 0,
-            transactions: vec![],
-            prev_hash: if let Some(lblock) = self.blocks.last() {
-                lblock.hash()
-            } else {
-                0.into()
+            header: BlockHeader {
+                nonce: 0,
+                prev_hash: if let Some(lblock) = self.blocks.last() {
+                    lblock.hash()
+                } else {
+                    0.into()
+                },
+                transactions_root: Block::transactions_root(&[]),
+                mined_by,
+                timestamp: self.clock.now().max(self.earliest_timestamp()),
             },
-            mined_by,
-            timestamp: now(),
+            transactions: vec![],
         }
     }
     pub fn add_block(&mut self, block: Block) -> Result<(), BlockValidationError> {
-        if let Some(lblock) = self.blocks.last() {
-            if lblock.hash() != block.prev_hash {
-                return Err(BlockValidationError::PrevHashMismatch);
-            }
-            if block.timestamp > now() || lblock.timestamp > block.timestamp {
-                return Err(BlockValidationError::InvalidTimestamp);
-            }
-        }
-
-        let new_difficulty = self.difficulty(&block);
+        let _span = debug_span!(
+            "validate_block",
+            hash = %format_args!("{:x}", block.hash()),
+            height = self.blocks.len()
+        )
+        .entered();
+        let new_difficulty = check_header(
+            self.blocks.last().map(|b| &b.header),
+            median_time_past(self.blocks.iter().rev().map(|b| &b.header)),
+            self.cur_dif,
+            &block.header,
+            self.latest_timestamp(),
+        )?;
 
-        if !mining::mined(&block, new_difficulty) {
-            return Err(BlockValidationError::NotMinedCorrectly);
+        if block.header.transactions_root != Block::transactions_root(&block.transactions) {
+            return Err(BlockValidationError::TransactionsRootMismatch);
         }
 
-        // check transactions
+        // checked against a copy, a block rejected halfway leaves the balances as they were
+        let mut balances = self.balances.clone();
+        let mut included = HashSet::new();
         for transaction in &block.transactions {
-            println!("Transaction inside a block detected {:?}", transaction);
-
-            let pub_kb = transaction.data.from.as_bytes();
-
+            trace!(?transaction, "checking transaction");
             if !transaction.valid() {
                 return Err(BlockValidationError::InvalidTransactionSignature);
             }
-            if &transaction.data.amount > self.balances.get(pub_kb).unwrap_or(&0) {
-                return Err(BlockValidationError::ExcessiveTransactionAmount);
+            let hash = transaction.hash();
+            if self.confirmed.contains(&hash) || !included.insert(hash) {
+                return Err(BlockValidationError::DuplicateTransaction);
             }
-            if let Some(balance) = self.balances.get_mut(pub_kb) {
-                *balance -= transaction.data.amount;
-            } else {
-                return Err(BlockValidationError::ExcessiveTransactionAmount);
+            match balances.get_mut(transaction.data.from.as_bytes()) {
+                Some(balance) if *balance >= transaction.data.amount => {
+                    *balance -= transaction.data.amount;
+                }
+                _ => return Err(BlockValidationError::ExcessiveTransactionAmount),
             }
         }
 
         for transaction in &block.transactions {
-            if let Some(balance) = self.balances.get_mut(transaction.data.to.as_bytes()) {
-                *balance += transaction.data.amount;
-            } else {
-                self.balances
-                    .insert(*transaction.data.to.as_bytes(), transaction.data.amount);
-            }
+            *balances.entry(*transaction.data.to.as_bytes()).or_insert(0) +=
+                transaction.data.amount;
         }
+        *balances
+            .entry(*block.header.mined_by.as_bytes())
+            .or_insert(0) += MINING_REW;
 
+        self.balances = balances;
+        self.confirmed.extend(included);
         self.weight += new_difficulty;
         self.cur_dif = new_difficulty;
-
-        let cur_bal = *self.balances.get(block.mined_by.as_bytes()).unwrap_or(&0);
-        self.balances
-            .insert(*block.mined_by.as_bytes(), cur_bal + MINING_REW);
-
         self.blocks.push(block);
 
         Ok(())
@@ -1,97 +1,56 @@
 use super::*;
-use rand::rngs::OsRng;
-use std::collections::VecDeque;
-
-pub struct Client {
-    pub key_pair: Keypair,
-    transactions: Arc<Mutex<VecDeque<Transaction>>>,
+use crate::mempool::MempoolError;
+use crate::node::NodeCommand;
+use futures::channel::{mpsc, oneshot};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    Rejected(MempoolError),
+    NodeStopped,
 }
 
-impl Client {
-    pub async fn start(transaction_topic: gossipsub::IdentTopic) -> Result<Self, Box<dyn Error>> {
-        let mut network_manager = NetworkManager::start(vec![transaction_topic.clone()]).await?;
-
-        let transactions: Arc<Mutex<VecDeque<Transaction>>> = Default::default();
-        let transactions_copy = transactions.clone();
-
-        rayon::spawn(move || {
-            let rt = tokio::runtime::Runtime::new().unwrap();
-            rt.block_on(async {
-                let mut transaction_stream = TransactionStream {
-                    transactions: transactions_copy,
-                };
-                loop {
-                    select! {
-                       transaction = transaction_stream => {
-                           network_manager.swarm.behaviour_mut().gossipsub.publish(transaction_topic.clone(), bincode::serialize(&transaction).unwrap());
-                       },
-                       event = network_manager.swarm.select_next_some() => match event {
-                           SwarmEvent::NewListenAddr { address, .. } => {
-                               //println!("Listening on {:?}", address);
-                           }
-                          SwarmEvent::Behaviour(p2p::OutEvent::Mdns(
-                               MdnsEvent::Discovered(list)
-                           )) => {
-                               //println!("NEW PEER DISCOVERED");
-                               for (peer, _) in list {
-                                   network_manager.swarm
-                                       .behaviour_mut()
-                                       .gossipsub
-                                       .add_explicit_peer(&peer);
-                               }
-                           }
-                           SwarmEvent::Behaviour(p2p::OutEvent::Mdns(MdnsEvent::Expired(
-                               list
-                           ))) => {
-                               //println!("PEER EXPIRED");
-                               for (peer, _) in list {
-                                   if !network_manager.swarm.behaviour_mut().mdns.has_node(&peer) {
-                                       network_manager.swarm
-                                           .behaviour_mut()
-                                           .gossipsub
-                                           .remove_explicit_peer(&peer);
-                                   }
-                               }
-                           },
-                           _ => {}
-                       }
-                    }
-                }
-            });
-        });
-
-        Ok(Self {
-            key_pair: Keypair::generate(&mut OsRng {}),
-            transactions,
-        })
-    }
-
-    pub fn send_transaction(&mut self, payee: PublicKey, amount: u64) {
-        let transaction = Transaction::new(payee, amount, &self.key_pair);
-        self.transactions.lock().unwrap().push_back(transaction);
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Rejected(MempoolError::InsufficientBalance) => {
+                write!(f, "insufficient balance")
+            }
+            SendError::Rejected(MempoolError::InvalidSignature) => write!(f, "invalid signature"),
+            SendError::NodeStopped => write!(f, "the node has stopped"),
+        }
     }
 }
 
-struct TransactionStream {
-    transactions: Arc<Mutex<VecDeque<Transaction>>>,
-}
-
-impl Future for TransactionStream {
-    type Output = Transaction;
+impl Error for SendError {}
 
-    fn poll(self: std::pin::Pin<&mut Self>, _: &mut task::Context<'_>) -> task::Poll<Self::Output> {
-        let mut transactions = self.transactions.lock().unwrap();
+/// A wallet, its transactions go straight into the mempool of the local node.
+pub struct Client {
+    pub key_pair: Keypair,
+    node: mpsc::UnboundedSender<NodeCommand>,
+}
 
-        if let Some(transaction) = transactions.pop_front() {
-            task::Poll::Ready(transaction)
-        } else {
-            task::Poll::Pending
+impl Client {
+    pub fn new(key_pair: Keypair, node: &Node) -> Self {
+        Self {
+            key_pair,
+            node: node.commands.clone(),
         }
     }
-}
 
-impl crate::future::FusedFuture for TransactionStream {
-    fn is_terminated(&self) -> bool {
-        false
+    /// Signs and submits a transaction, returning its hash once the mempool took it.
+    /// The mempool counts the wallet's pending transactions against its balance.
+    pub fn send_transaction(&self, payee: PublicKey, amount: u64) -> Result<Hash, SendError> {
+        let transaction = Transaction::new(payee, amount, &self.key_pair);
+        let hash = transaction.hash();
+        let (reply, accepted) = oneshot::channel();
+        let _ = self
+            .node
+            .unbounded_send(NodeCommand::SubmitTransaction(Box::new(transaction), reply));
+        match task::block_on(accepted) {
+            Ok(Ok(())) => Ok(hash),
+            Ok(Err(reason)) => Err(SendError::Rejected(reason)),
+            Err(oneshot::Canceled) => Err(SendError::NodeStopped),
+        }
     }
 }
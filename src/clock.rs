@@ -0,0 +1,115 @@
+use super::*;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the current time in seconds since the Unix epoch, used to stamp new blocks
+/// and to reject blocks from the future.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+
+    /// The time of the machine itself, without corrections, which is what peers are told.
+    fn local_now(&self) -> u64 {
+        self.now()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        let duration = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+        duration.as_secs()
+    }
+}
+
+/// A clock that only moves when told to, for tests and simulations.
+#[derive(Debug, Default)]
+pub struct MockClock {
+    time: AtomicU64,
+}
+
+impl MockClock {
+    pub fn new(time: u64) -> Self {
+        Self {
+            time: AtomicU64::new(time),
+        }
+    }
+
+    pub fn set(&self, time: u64) {
+        self.time.store(time, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        self.time.fetch_add(seconds, Ordering::Relaxed);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.time.load(Ordering::Relaxed)
+    }
+}
+
+// Peers whose clocks are further off than this many seconds don't move ours
+const MAX_TIME_ADJUSTMENT: i64 = 10 * 60;
+// Enough peers to outvote a few with a wrong clock
+const MIN_TIME_SAMPLES: usize = 5;
+
+/// Local time corrected by the median offset of the clocks peers report, so that a node
+/// whose own clock is off still accepts the blocks the rest of the network does.
+pub struct NetworkClock {
+    clock: Arc<dyn Clock>,
+    offsets: Mutex<HashMap<PeerId, i64>>,
+}
+
+impl NetworkClock {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            offsets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records the time a peer reported, replacing what it reported before.
+    pub fn add_sample(&self, peer: PeerId, time: u64) {
+        let offset = time as i64 - self.clock.now() as i64;
+        self.offsets.lock().unwrap().insert(peer, offset);
+    }
+
+    /// Forgets a peer's time once it disconnects, so only connected peers are counted.
+    pub fn remove_sample(&self, peer: &PeerId) {
+        self.offsets.lock().unwrap().remove(peer);
+    }
+
+    /// How many seconds the network is ahead of the local clock.
+    pub fn offset(&self) -> i64 {
+        let offsets = self.offsets.lock().unwrap();
+        if offsets.len() < MIN_TIME_SAMPLES {
+            return 0;
+        }
+        let mut offsets: Vec<i64> = offsets.values().copied().collect();
+        offsets.sort_unstable();
+        let median = offsets[offsets.len() / 2];
+        if median.abs() > MAX_TIME_ADJUSTMENT {
+            return 0;
+        }
+        median
+    }
+}
+
+impl Clock for NetworkClock {
+    fn now(&self) -> u64 {
+        self.clock.now().saturating_add_signed(self.offset())
+    }
+
+    fn local_now(&self) -> u64 {
+        self.clock.local_now()
+    }
+}
+
+pub(crate) fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
@@ -0,0 +1,386 @@
+use super::*;
+use crate::blockchain::BlockValidationError;
+use crate::mempool::Mempool;
+use crate::peers::Misbehaviour;
+use crate::sync::{Status, SyncCodec, SyncRequest};
+use libp2p::request_response::{RequestId, RequestResponse};
+use std::collections::HashMap;
+
+/// Short transaction ids, the low 64 bits of the transaction hash.
+pub type ShortId = u64;
+
+pub fn short_id(transaction: &Transaction) -> ShortId {
+    transaction.hash().low_u64()
+}
+
+/// A block announcement carrying short ids instead of transactions,
+/// receivers fill them in from their own mempool.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    pub short_ids: Vec<ShortId>,
+}
+
+impl CompactBlock {
+    pub fn new(block: &Block) -> Self {
+        Self {
+            header: block.header.clone(),
+            short_ids: block.transactions.iter().map(short_id).collect(),
+        }
+    }
+}
+
+/// A compact block being rebuilt, with gaps where transactions are missing.
+#[derive(Debug)]
+pub struct PartialBlock {
+    pub header: BlockHeader,
+    short_ids: Vec<ShortId>,
+    transactions: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    pub fn new(compact: CompactBlock, mempool: &Mempool) -> Self {
+        let known: HashMap<ShortId, &Transaction> = mempool
+            .transactions()
+            .iter()
+            .map(|t| (short_id(t), t))
+            .collect();
+        let transactions = compact
+            .short_ids
+            .iter()
+            .map(|id| known.get(id).map(|t| (*t).clone()))
+            .collect();
+
+        Self {
+            header: compact.header,
+            short_ids: compact.short_ids,
+            transactions,
+        }
+    }
+
+    pub fn missing(&self) -> Vec<usize> {
+        self.transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| t.is_none())
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    // Two transactions sharing a short id make the root mismatch, then all of them are fetched
+    fn forget(&mut self) {
+        self.transactions.iter_mut().for_each(|t| *t = None);
+    }
+
+    /// Fills the gaps in order, returning false if `transactions` don't fit them.
+    pub fn fill(&mut self, transactions: Vec<Transaction>) -> bool {
+        let missing = self.missing();
+        if missing.len() != transactions.len() {
+            return false;
+        }
+        for (i, transaction) in missing.into_iter().zip(transactions) {
+            if short_id(&transaction) != self.short_ids[i] {
+                return false;
+            }
+            self.transactions[i] = Some(transaction);
+        }
+        true
+    }
+
+    /// The block, once every transaction is there and they match the header.
+    pub fn complete(&self) -> Option<Block> {
+        let transactions = self
+            .transactions
+            .iter()
+            .cloned()
+            .collect::<Option<Vec<_>>>()?;
+        if Block::transactions_root(&transactions) != self.header.transactions_root {
+            return None;
+        }
+        Some(Block {
+            header: self.header.clone(),
+            transactions,
+        })
+    }
+}
+
+pub enum Reconstruction {
+    // every transaction was in the mempool, the block can be connected
+    Complete(Box<Block>),
+    // waiting for the announcer to send the missing transactions
+    Pending,
+    // nothing to connect, with how the announcement should be treated
+    Done(Result<MessageAcceptance, Misbehaviour>),
+}
+
+/// Rebuilds announced compact blocks, asking their announcers for the transactions
+/// our mempool doesn't have. The announcements are validated only once the block is connected.
+#[derive(Default)]
+pub struct CompactRelay {
+    requests: HashMap<RequestId, (MessageId, PeerId, PartialBlock)>,
+}
+
+impl CompactRelay {
+    pub fn is_pending(&self, request_id: &RequestId) -> bool {
+        self.requests.contains_key(request_id)
+    }
+
+    pub fn on_compact_block(
+        &mut self,
+        sync: &mut RequestResponse<SyncCodec>,
+        active_blockchain: &Blockchain,
+        mempool: &Mempool,
+        message_id: MessageId,
+        source: PeerId,
+        compact: CompactBlock,
+    ) -> Reconstruction {
+        let hash = compact.header.hash();
+        if active_blockchain
+            .blocks
+            .iter()
+            .rev()
+            .any(|b| b.hash() == hash)
+        {
+            return Reconstruction::Done(Ok(MessageAcceptance::Ignore));
+        }
+        if active_blockchain.blocks.last().map(|b| b.hash()) != Some(compact.header.prev_hash) {
+            // it doesn't extend our chain, a heavier one gets picked up by the synchronizer
+            sync.send_request(&source, SyncRequest::Status(Status::new(active_blockchain)));
+            return Reconstruction::Done(Ok(MessageAcceptance::Ignore));
+        }
+        // proof of work before anything gets requested for it
+        let difficulty = active_blockchain.difficulty(&compact.header);
+        if !mining::mined(&compact.header, difficulty) {
+            return Reconstruction::Done(Err(Misbehaviour::InvalidBlock(
+                BlockValidationError::NotMinedCorrectly,
+            )));
+        }
+
+        let mut partial = PartialBlock::new(compact, mempool);
+        if partial.missing().is_empty() {
+            match partial.complete() {
+                Some(block) => return Reconstruction::Complete(Box::new(block)),
+                None => partial.forget(),
+            }
+        }
+        let request_id =
+            sync.send_request(&source, SyncRequest::Transactions(hash, partial.missing()));
+        self.requests
+            .insert(request_id, (message_id, source, partial));
+        Reconstruction::Pending
+    }
+
+    /// Completes a block with the transactions its announcer sent, returning the announcement
+    /// along with how far the block got. An announcer that reorged away from the block sends
+    /// none, then the whole block is asked for instead.
+    pub fn on_transactions(
+        &mut self,
+        sync: &mut RequestResponse<SyncCodec>,
+        request_id: RequestId,
+        transactions: Vec<Transaction>,
+    ) -> Option<(MessageId, PeerId, Reconstruction)> {
+        let (message_id, source, mut partial) = self.requests.remove(&request_id)?;
+        let reconstruction = if transactions.is_empty() {
+            let request_id =
+                sync.send_request(&source, SyncRequest::Blocks(vec![partial.header.hash()]));
+            self.requests
+                .insert(request_id, (message_id.clone(), source, partial));
+            Reconstruction::Pending
+        } else {
+            match partial
+                .fill(transactions)
+                .then(|| partial.complete())
+                .flatten()
+            {
+                Some(block) => Reconstruction::Complete(Box::new(block)),
+                None => Reconstruction::Done(Err(Misbehaviour::WrongBlockBodies)),
+            }
+        };
+        Some((message_id, source, reconstruction))
+    }
+
+    /// Completes a block with the whole block its announcer sent instead of the transactions.
+    pub fn on_blocks(
+        &mut self,
+        request_id: RequestId,
+        blocks: Vec<Block>,
+    ) -> Option<(MessageId, PeerId, Reconstruction)> {
+        let (message_id, source, partial) = self.requests.remove(&request_id)?;
+        let reconstruction = match blocks.into_iter().next() {
+            Some(block)
+                if block.hash() == partial.header.hash()
+                    && Block::transactions_root(&block.transactions)
+                        == block.header.transactions_root =>
+            {
+                Reconstruction::Complete(Box::new(block))
+            }
+            Some(_) => Reconstruction::Done(Err(Misbehaviour::WrongBlockBodies)),
+            // gone from the announcer's chain as well, nothing to hold against it
+            None => Reconstruction::Done(Ok(MessageAcceptance::Ignore)),
+        };
+        Some((message_id, source, reconstruction))
+    }
+
+    /// Gives up on a block whose transactions couldn't be fetched.
+    pub fn on_failure(&mut self, request_id: RequestId) -> Option<(MessageId, PeerId)> {
+        let (message_id, source, _) = self.requests.remove(&request_id)?;
+        Some((message_id, source))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_pair() -> Keypair {
+        Keypair::generate(&mut rand::rngs::OsRng {})
+    }
+
+    // a block paying out of a funded wallet, with a mempool holding `pooled` of its transactions
+    fn block_and_mempool(pooled: &[usize]) -> (Block, Mempool) {
+        let (_, block, mempool) = chain_block_and_mempool(pooled);
+        (block, mempool)
+    }
+
+    // the same, mined on top of the returned chain
+    fn chain_block_and_mempool(pooled: &[usize]) -> (Blockchain, Block, Mempool) {
+        let payer = key_pair();
+        let clock = Arc::new(MockClock::new(GENESIS_TIMESTAMP + TIME_BASE));
+        let mut blockchain = Blockchain::new(&ChainSpec::new("test"), clock);
+        let mut funding = blockchain.generate_block(payer.public);
+        let difficulty = blockchain.difficulty(&funding.header);
+        while !mining::mined(&funding.header, difficulty) {
+            funding.header.nonce += 1;
+        }
+        blockchain.add_block(funding).unwrap();
+
+        let mut block = blockchain.generate_block(payer.public);
+        let mut mempool = Mempool::default();
+        for i in 0..4 {
+            let transaction = Transaction::new(key_pair().public, i + 1, &payer);
+            if pooled.contains(&(i as usize)) {
+                mempool.insert(&blockchain, transaction.clone()).unwrap();
+            }
+            block.add_transaction(transaction);
+        }
+        let difficulty = blockchain.difficulty(&block.header);
+        while !mining::mined(&block.header, difficulty) {
+            block.header.nonce += 1;
+        }
+        (blockchain, block, mempool)
+    }
+
+    // a relay waiting for the announcer to send the transactions missing from the mempool
+    fn announced(pooled: &[usize]) -> (CompactRelay, RequestResponse<SyncCodec>, Block, RequestId) {
+        let (blockchain, block, mempool) = chain_block_and_mempool(pooled);
+        let mut relay = CompactRelay::default();
+        let mut sync = crate::sync::behaviour("test");
+        let reconstruction = relay.on_compact_block(
+            &mut sync,
+            &blockchain,
+            &mempool,
+            MessageId::new(b"announcement"),
+            PeerId::random(),
+            CompactBlock::new(&block),
+        );
+        assert!(matches!(reconstruction, Reconstruction::Pending));
+        let request_id = *relay.requests.keys().next().unwrap();
+        (relay, sync, block, request_id)
+    }
+
+    #[test]
+    fn blocks_with_every_transaction_pooled_complete_right_away() {
+        let (block, mempool) = block_and_mempool(&[0, 1, 2, 3]);
+        let partial = PartialBlock::new(CompactBlock::new(&block), &mempool);
+        assert!(partial.missing().is_empty());
+        assert_eq!(partial.complete().map(|b| b.hash()), Some(block.hash()));
+    }
+
+    #[test]
+    fn missing_transactions_are_filled_in_order() {
+        let (block, mempool) = block_and_mempool(&[0, 2]);
+        let mut partial = PartialBlock::new(CompactBlock::new(&block), &mempool);
+        assert_eq!(partial.missing(), vec![1, 3]);
+        assert!(partial.complete().is_none());
+
+        let missing = vec![block.transactions[1].clone(), block.transactions[3].clone()];
+        assert!(partial.fill(missing));
+        assert!(partial.missing().is_empty());
+        assert_eq!(partial.complete().map(|b| b.hash()), Some(block.hash()));
+    }
+
+    #[test]
+    fn transactions_that_dont_fit_the_gaps_are_refused() {
+        let (block, mempool) = block_and_mempool(&[0, 2]);
+        let mut partial = PartialBlock::new(CompactBlock::new(&block), &mempool);
+
+        assert!(!partial.fill(vec![block.transactions[1].clone()]));
+        assert!(!partial.fill(vec![
+            block.transactions[3].clone(),
+            block.transactions[1].clone(),
+        ]));
+        assert!(partial.complete().is_none());
+    }
+
+    #[test]
+    fn transactions_not_matching_the_header_leave_the_block_incomplete() {
+        let (mut block, mempool) = block_and_mempool(&[0, 1, 2, 3]);
+        block.header.transactions_root = Block::transactions_root(&[]);
+        let mut partial = PartialBlock::new(CompactBlock::new(&block), &mempool);
+        assert!(partial.complete().is_none());
+
+        // then everything is asked for again
+        partial.forget();
+        assert_eq!(partial.missing(), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn announced_blocks_complete_with_the_missing_transactions() {
+        let (mut relay, mut sync, block, request_id) = announced(&[0, 2]);
+        let missing = vec![block.transactions[1].clone(), block.transactions[3].clone()];
+        let (_, _, reconstruction) = relay
+            .on_transactions(&mut sync, request_id, missing)
+            .unwrap();
+        assert!(matches!(reconstruction, Reconstruction::Complete(b) if b.hash() == block.hash()));
+    }
+
+    #[test]
+    fn announcers_sending_wrong_transactions_are_reported() {
+        let (mut relay, mut sync, block, request_id) = announced(&[0, 2]);
+        let wrong = vec![block.transactions[0].clone(), block.transactions[2].clone()];
+        let (_, _, reconstruction) = relay.on_transactions(&mut sync, request_id, wrong).unwrap();
+        assert!(matches!(
+            reconstruction,
+            Reconstruction::Done(Err(Misbehaviour::WrongBlockBodies))
+        ));
+    }
+
+    #[test]
+    fn announcers_without_the_transactions_are_asked_for_the_whole_block() {
+        let (mut relay, mut sync, block, request_id) = announced(&[0, 2]);
+        let (_, _, reconstruction) = relay
+            .on_transactions(&mut sync, request_id, vec![])
+            .unwrap();
+        assert!(matches!(reconstruction, Reconstruction::Pending));
+        assert!(!relay.is_pending(&request_id));
+        let request_id = *relay.requests.keys().next().unwrap();
+
+        let (_, _, reconstruction) = relay.on_blocks(request_id, vec![block.clone()]).unwrap();
+        assert!(matches!(reconstruction, Reconstruction::Complete(b) if b.hash() == block.hash()));
+    }
+
+    #[test]
+    fn announcers_that_no_longer_have_the_block_are_not_reported() {
+        let (mut relay, mut sync, _, request_id) = announced(&[0, 2]);
+        relay
+            .on_transactions(&mut sync, request_id, vec![])
+            .unwrap();
+        let request_id = *relay.requests.keys().next().unwrap();
+
+        let (_, _, reconstruction) = relay.on_blocks(request_id, vec![]).unwrap();
+        assert!(matches!(
+            reconstruction,
+            Reconstruction::Done(Ok(MessageAcceptance::Ignore))
+        ));
+    }
+}
@@ -0,0 +1,108 @@
+use super::*;
+use crate::mining::{MiningConfig, DEFAULT_MINING_THREADS};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+// Looked for in the data directory unless another file is given
+pub const CONFIG_FILE: &str = "config.toml";
+
+// Relative to the working directory, nodes sharing a machine each need their own
+pub const DEFAULT_DATA_DIR: &str = "data";
+
+/// Everything a node is started with, read from a TOML file.
+/// Settings the file leaves out keep their defaults, unknown ones are an error.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Keys, chain, mempool and logs
+    pub data_dir: PathBuf,
+    // Chain ID, only peers on the same network are talked to
+    pub network: String,
+    pub listen: Vec<Multiaddr>,
+    pub announce: Vec<Multiaddr>,
+    pub bootstrap: Vec<Multiaddr>,
+    pub mining: bool,
+    pub mining_threads: usize,
+    // Hex public key block rewards are paid to, the node's wallet when not set
+    pub reward_address: Option<String>,
+    pub rpc: SocketAddr,
+    pub subscriptions: SocketAddr,
+    pub metrics: SocketAddr,
+    // `RUST_LOG` style filter
+    pub log: String,
+    // The data directory when not set
+    pub log_dir: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            data_dir: DEFAULT_DATA_DIR.into(),
+            network: ChainSpec::default().chain_id,
+            listen: vec![],
+            announce: vec![],
+            bootstrap: vec![],
+            mining: true,
+            mining_threads: DEFAULT_MINING_THREADS,
+            reward_address: None,
+            rpc: rpc::DEFAULT_RPC_ADDRESS.parse().unwrap(),
+            subscriptions: subscriptions::DEFAULT_SUBSCRIPTION_ADDRESS.parse().unwrap(),
+            metrics: metrics::DEFAULT_METRICS_ADDRESS.parse().unwrap(),
+            log: logging::DEFAULT_LOG_FILTER.to_string(),
+            log_dir: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        toml::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    /// `config.toml` in `data_dir`, the defaults there when it has none.
+    pub fn load_from_data_dir(data_dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let path = data_dir.join(CONFIG_FILE);
+        let config = if path.exists() {
+            Self::load(&path)?
+        } else {
+            Self::default()
+        };
+        Ok(Self {
+            data_dir: data_dir.to_path_buf(),
+            ..config
+        })
+    }
+
+    pub fn network_config(&self) -> NetworkConfig {
+        NetworkConfig {
+            transport: TransportKind::Tcp,
+            chain: ChainSpec::new(&self.network),
+            bootstrap_peers: self.bootstrap.clone(),
+            data_dir: Some(self.data_dir.clone()),
+            listen_addresses: self.listen.clone(),
+            announce_addresses: self.announce.clone(),
+        }
+    }
+
+    pub fn mining_config(&self) -> MiningConfig {
+        MiningConfig {
+            enabled: self.mining,
+            threads: self.mining_threads,
+        }
+    }
+
+    pub fn reward_address(&self) -> Result<Option<PublicKey>, Box<dyn Error>> {
+        match &self.reward_address {
+            Some(address) => Ok(Some(
+                rpc::parse_address(address).map_err(|_| "invalid reward address")?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    pub fn log_dir(&self) -> &Path {
+        self.log_dir.as_deref().unwrap_or(&self.data_dir)
+    }
+}
@@ -0,0 +1,375 @@
+use super::*;
+use crate::client::SendError;
+use crate::events::{Event, EventLog};
+use crate::mempool::MempoolError;
+use crossterm::event::{self, Event as TerminalEvent, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use std::io;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{self, error::TryRecvError};
+use tui::backend::{Backend, CrosstermBackend};
+use tui::layout::{Constraint, Direction, Layout};
+use tui::style::{Modifier, Style};
+use tui::text::{Span, Spans};
+use tui::widgets::{self, Borders, List, ListItem, Paragraph};
+use tui::{Frame, Terminal};
+
+// Without node events the screen is still redrawn this often, for the hashrate
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+// How long to wait for a key press before looking for node events again
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const RECENT_BLOCKS: usize = 10;
+const RECENT_EVENTS: usize = 50;
+// Hashes and addresses in lists are cut to this many hex digits
+const SHORT_HEX: usize = 16;
+
+enum Prompt {
+    Payee(String),
+    Amount { payee: PublicKey, amount: String },
+    Peer(String),
+}
+
+impl Prompt {
+    fn label(&self) -> &str {
+        match self {
+            Prompt::Payee(_) => "Payee address",
+            Prompt::Amount { .. } => "Amount",
+            Prompt::Peer(_) => "Peer address",
+        }
+    }
+
+    fn value(&self) -> &str {
+        match self {
+            Prompt::Payee(text) | Prompt::Amount { amount: text, .. } | Prompt::Peer(text) => text,
+        }
+    }
+
+    fn text(&mut self) -> &mut String {
+        match self {
+            Prompt::Payee(text) | Prompt::Amount { amount: text, .. } | Prompt::Peer(text) => text,
+        }
+    }
+}
+
+// What gets drawn, taken from the node without holding its locks while drawing
+struct Snapshot {
+    height: usize,
+    tip: Option<Hash>,
+    difficulty: u32,
+    weight: u32,
+    sync: String,
+    // from the tip down
+    blocks: Vec<Block>,
+    mempool: usize,
+    peers: Vec<(PeerId, i32)>,
+    balance: u64,
+}
+
+impl Snapshot {
+    fn take(node: &Node, wallet: &Client) -> Self {
+        // one lock at a time, the node takes them in its own order
+        let sync = node.sync_progress.lock().unwrap().to_string();
+        let mempool = node.mempool.lock().unwrap().len();
+        let peers = node.connected_peers();
+        let blockchain = node.active_blockchain.lock().unwrap();
+        Self {
+            height: blockchain.blocks.len() - 1,
+            tip: blockchain.blocks.last().map(|b| b.hash()),
+            difficulty: blockchain.cur_dif,
+            weight: blockchain.weight,
+            sync,
+            blocks: blockchain
+                .blocks
+                .iter()
+                .rev()
+                .take(RECENT_BLOCKS)
+                .cloned()
+                .collect(),
+            mempool,
+            peers,
+            balance: *blockchain
+                .balances
+                .get(wallet.key_pair.public.as_bytes())
+                .unwrap_or(&0),
+        }
+    }
+}
+
+/// A live view of a running node in the terminal, redrawn as node events come in.
+/// Keys send coins from the wallet, switch mining on and off and add peers.
+pub struct Dashboard {
+    node: Arc<Node>,
+    wallet: Arc<Client>,
+    events: EventLog,
+    receiver: broadcast::Receiver<Event>,
+    // the hash counter when the hashrate was last sampled
+    hashes: (Instant, u64),
+    hashrate: f64,
+    prompt: Option<Prompt>,
+    // how the last action went
+    status: String,
+}
+
+impl Dashboard {
+    pub fn new(node: Arc<Node>, wallet: Arc<Client>) -> Self {
+        Self {
+            events: EventLog::start(&node.events, RECENT_EVENTS),
+            receiver: node.events.subscribe(),
+            hashes: (Instant::now(), node.metrics.hashes.get()),
+            hashrate: 0.0,
+            prompt: None,
+            status: String::new(),
+            node,
+            wallet,
+        }
+    }
+
+    /// Takes over the terminal until `q` is pressed.
+    pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
+        terminal::enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        execute!(stdout, EnterAlternateScreen)?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+
+        let result = self.event_loop(&mut terminal);
+
+        // the terminal is given back even when drawing failed
+        terminal::disable_raw_mode()?;
+        execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+        terminal.show_cursor()?;
+        result
+    }
+
+    fn event_loop<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<(), Box<dyn Error>> {
+        let mut redraw = true;
+        let mut refreshed = Instant::now();
+        loop {
+            if redraw {
+                self.draw(terminal)?;
+                redraw = false;
+            }
+            if event::poll(POLL_INTERVAL)? {
+                // anything else is a resize or focus change, which only needs a redraw
+                if let TerminalEvent::Key(key) = event::read()? {
+                    if !self.handle_key(key) {
+                        return Ok(());
+                    }
+                }
+                redraw = true;
+            }
+            while let Ok(_) | Err(TryRecvError::Lagged(_)) = self.receiver.try_recv() {
+                redraw = true;
+            }
+            if refreshed.elapsed() >= REFRESH_INTERVAL {
+                self.sample_hashrate();
+                refreshed = Instant::now();
+                redraw = true;
+            }
+        }
+    }
+
+    fn sample_hashrate(&mut self) {
+        let (then, hashes_then) = self.hashes;
+        let (now, hashes) = (Instant::now(), self.node.metrics.hashes.get());
+        self.hashrate = (hashes - hashes_then) as f64 / (now - then).as_secs_f64();
+        self.hashes = (now, hashes);
+    }
+
+    /// Acts on a key press, returning false once the dashboard should close.
+    pub fn handle_key(&mut self, key: KeyEvent) -> bool {
+        // raw mode turns Ctrl+C into a key press
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            return false;
+        }
+        let Some(mut prompt) = self.prompt.take() else {
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return false,
+                KeyCode::Char('m') => {
+                    let mining = !self.node.is_mining();
+                    self.node.set_mining(mining);
+                    self.status = format!("Mining {}", if mining { "on" } else { "off" });
+                }
+                KeyCode::Char('s') => self.prompt = Some(Prompt::Payee(String::new())),
+                KeyCode::Char('a') => self.prompt = Some(Prompt::Peer(String::new())),
+                _ => {}
+            }
+            return true;
+        };
+        self.prompt = match key.code {
+            KeyCode::Esc => None,
+            KeyCode::Enter => self.submit(prompt),
+            KeyCode::Backspace => {
+                prompt.text().pop();
+                Some(prompt)
+            }
+            KeyCode::Char(c) => {
+                prompt.text().push(c);
+                Some(prompt)
+            }
+            _ => Some(prompt),
+        };
+        true
+    }
+
+    // Returns the next prompt when the answer leads to one
+    fn submit(&mut self, prompt: Prompt) -> Option<Prompt> {
+        match prompt {
+            Prompt::Payee(address) => match rpc::parse_address(address.trim()) {
+                Ok(payee) => {
+                    return Some(Prompt::Amount {
+                        payee,
+                        amount: String::new(),
+                    })
+                }
+                Err(_) => self.status = "Wrong payee address format!".to_string(),
+            },
+            Prompt::Amount { payee, amount } => match amount.trim().parse::<u64>() {
+                Ok(amount) => match self.wallet.send_transaction(payee, amount) {
+                    Ok(hash) => {
+                        self.status = format!("Sent {} coins in transaction {:x}", amount, hash)
+                    }
+                    Err(SendError::Rejected(MempoolError::InsufficientBalance)) => {
+                        self.status = "You don't have that many coins!".to_string()
+                    }
+                    Err(e) => self.status = format!("Couldn't send the coins: {}", e),
+                },
+                Err(_) => self.status = "Wrong amount format!".to_string(),
+            },
+            Prompt::Peer(address) => match address.trim().parse::<Multiaddr>() {
+                Ok(address) => {
+                    self.status = format!("Dialing {}", address);
+                    self.node.add_peer(address);
+                }
+                Err(_) => self.status = "Wrong peer address format!".to_string(),
+            },
+        }
+        None
+    }
+
+    pub fn draw<B: Backend>(&self, terminal: &mut Terminal<B>) -> io::Result<()> {
+        let snapshot = Snapshot::take(&self.node, &self.wallet);
+        terminal.draw(|frame| self.render(frame, &snapshot))?;
+        Ok(())
+    }
+
+    fn render<B: Backend>(&self, frame: &mut Frame<B>, snapshot: &Snapshot) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(4),
+                Constraint::Length(4),
+                Constraint::Min(6),
+                Constraint::Length(8),
+                Constraint::Length(1),
+            ])
+            .split(frame.size());
+        let middle = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(65), Constraint::Percentage(35)])
+            .split(rows[2]);
+
+        let chain = vec![
+            Spans::from(vec![
+                field("Height", snapshot.height),
+                field("Difficulty", snapshot.difficulty),
+                field("Weight", snapshot.weight),
+                field("Sync", &snapshot.sync),
+            ]),
+            Spans::from(field(
+                "Tip",
+                snapshot
+                    .tip
+                    .map(|tip| format!("{:x}", tip))
+                    .unwrap_or_default(),
+            )),
+        ];
+        frame.render_widget(Paragraph::new(chain).block(block("Chain")), rows[0]);
+
+        let mining = if self.node.is_mining() { "on" } else { "off" };
+        let node = vec![
+            Spans::from(vec![
+                field("Mining", mining),
+                field("Hashrate", format!("{:.0} H/s", self.hashrate)),
+                field("Mempool", format!("{} transactions", snapshot.mempool)),
+                field("Peers", snapshot.peers.len()),
+            ]),
+            Spans::from(vec![
+                field("Wallet", hex::encode(self.wallet.key_pair.public)),
+                field("Balance", snapshot.balance),
+            ]),
+        ];
+        frame.render_widget(Paragraph::new(node).block(block("Node")), rows[1]);
+
+        let blocks: Vec<ListItem> = snapshot
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(depth, b)| {
+                let mut lines = vec![Spans::from(format!(
+                    "{:>6}  {}  {} transactions",
+                    snapshot.height - depth,
+                    short(format!("{:x}", b.hash())),
+                    b.transactions.len()
+                ))];
+                lines.extend(b.transactions.iter().map(|t| {
+                    Spans::from(format!(
+                        "        {} -> {}  {}",
+                        short(hex::encode(t.data.from)),
+                        short(hex::encode(t.data.to)),
+                        t.data.amount
+                    ))
+                }));
+                ListItem::new(lines)
+            })
+            .collect();
+        frame.render_widget(List::new(blocks).block(block("Recent blocks")), middle[0]);
+
+        let peers: Vec<ListItem> = snapshot
+            .peers
+            .iter()
+            .map(|(peer, score)| ListItem::new(format!("{}  {}", peer, score)))
+            .collect();
+        frame.render_widget(List::new(peers).block(block("Peers")), middle[1]);
+
+        let events: Vec<ListItem> = self
+            .events
+            .recent()
+            .iter()
+            .rev()
+            .map(|event| ListItem::new(event.to_string()))
+            .collect();
+        frame.render_widget(List::new(events).block(block("Events")), rows[3]);
+
+        let footer = match &self.prompt {
+            Some(prompt) => format!(
+                "{}: {}_   [enter] ok  [esc] cancel",
+                prompt.label(),
+                prompt.value()
+            ),
+            None => format!(
+                "[s] send  [m] mining on/off  [a] add peer  [q] quit   {}",
+                self.status
+            ),
+        };
+        frame.render_widget(Paragraph::new(footer), rows[4]);
+    }
+}
+
+fn block(title: &str) -> widgets::Block<'_> {
+    widgets::Block::default()
+        .title(Span::styled(
+            title,
+            Style::default().add_modifier(Modifier::BOLD),
+        ))
+        .borders(Borders::ALL)
+}
+
+fn field(name: &str, value: impl ToString) -> Span<'static> {
+    Span::raw(format!("{} {}   ", name, value.to_string()))
+}
+
+fn short(mut hex: String) -> String {
+    hex.truncate(SHORT_HEX);
+    hex
+}
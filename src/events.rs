@@ -0,0 +1,267 @@
+use super::*;
+use crate::mempool::MempoolError;
+use crate::rpc::{block_json, hex_hash, transaction_json};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::fmt;
+use tokio::sync::broadcast;
+
+// Events a subscriber can fall behind by before it starts missing them
+const EVENT_CAPACITY: usize = 1024;
+
+/// Everything the node does that other components may want to react to.
+/// Heights count from the genesis block at 0.
+#[derive(Debug, Clone)]
+pub enum Event {
+    // a block was added to the tip of the active chain
+    BlockConnected {
+        block: Block,
+        height: usize,
+    },
+    // a block was taken off the active chain for a heavier fork, sent newest first
+    BlockDisconnected {
+        block: Block,
+        height: usize,
+    },
+    // sent once the active chain is done changing, after the blocks connected and disconnected
+    TipChanged {
+        hash: Hash,
+        height: usize,
+        weight: u32,
+        difficulty: u32,
+    },
+    // a transaction entered the mempool
+    TxReceived(Transaction),
+    TxRejected {
+        transaction: Transaction,
+        reason: MempoolError,
+    },
+    PeerConnected(PeerId),
+    PeerDisconnected(PeerId),
+    // a block our miner sealed was connected to the active chain
+    MiningSolved(Block),
+}
+
+impl Event {
+    /// Whether the address paid, got paid or mined in what the event is about.
+    pub fn involves(&self, address: &PublicKey) -> bool {
+        let transaction_involves =
+            |t: &Transaction| &t.data.from == address || &t.data.to == address;
+        match self {
+            Event::BlockConnected { block, .. }
+            | Event::BlockDisconnected { block, .. }
+            | Event::MiningSolved(block) => {
+                &block.header.mined_by == address
+                    || block.transactions.iter().any(transaction_involves)
+            }
+            Event::TxReceived(transaction) | Event::TxRejected { transaction, .. } => {
+                transaction_involves(transaction)
+            }
+            Event::TipChanged { .. } | Event::PeerConnected(_) | Event::PeerDisconnected(_) => {
+                false
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        match self {
+            Event::BlockConnected { block, height } => json!({
+                "event": "block_connected",
+                "height": height,
+                "block": block_json(block),
+            }),
+            Event::BlockDisconnected { block, height } => json!({
+                "event": "block_disconnected",
+                "height": height,
+                "block": block_json(block),
+            }),
+            Event::TipChanged {
+                hash,
+                height,
+                weight,
+                difficulty,
+            } => json!({
+                "event": "tip_changed",
+                "hash": hex_hash(*hash),
+                "height": height,
+                "weight": weight,
+                "difficulty": difficulty,
+            }),
+            Event::TxReceived(transaction) => json!({
+                "event": "tx_received",
+                "transaction": transaction_json(transaction),
+            }),
+            Event::TxRejected {
+                transaction,
+                reason,
+            } => json!({
+                "event": "tx_rejected",
+                "transaction": transaction_json(transaction),
+                "reason": format!("{:?}", reason),
+            }),
+            Event::PeerConnected(peer) => json!({
+                "event": "peer_connected",
+                "peer": peer.to_string(),
+            }),
+            Event::PeerDisconnected(peer) => json!({
+                "event": "peer_disconnected",
+                "peer": peer.to_string(),
+            }),
+            Event::MiningSolved(block) => json!({
+                "event": "mining_solved",
+                "block": block_json(block),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::BlockConnected { block, height } => {
+                write!(f, "Block {:x} connected at height {}", block.hash(), height)
+            }
+            Event::BlockDisconnected { block, height } => {
+                write!(
+                    f,
+                    "Block {:x} disconnected from height {}",
+                    block.hash(),
+                    height
+                )
+            }
+            Event::TipChanged {
+                hash,
+                height,
+                weight,
+                difficulty,
+            } => write!(
+                f,
+                "New tip {:x} at height {}, weight {}, difficulty {}",
+                hash, height, weight, difficulty
+            ),
+            Event::TxReceived(transaction) => {
+                write!(f, "Transaction {:x} received", transaction.hash())
+            }
+            Event::TxRejected {
+                transaction,
+                reason,
+            } => write!(
+                f,
+                "Transaction {:x} rejected: {:?}",
+                transaction.hash(),
+                reason
+            ),
+            Event::PeerConnected(peer) => write!(f, "Peer {} connected", peer),
+            Event::PeerDisconnected(peer) => write!(f, "Peer {} disconnected", peer),
+            Event::MiningSolved(block) => write!(f, "Mined block {:x}", block.hash()),
+        }
+    }
+}
+
+/// Broadcasts node events to any number of subscribers, each getting every event
+/// published after it subscribed. Subscribers that fall too far behind miss the oldest ones.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+}
+
+impl EventBus {
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    pub fn publish(&self, event: Event) {
+        // nobody listening is fine
+        let _ = self.sender.send(event);
+    }
+
+    /// Publishes the block just added to the tip of `blockchain`.
+    pub fn block_connected(&self, blockchain: &Blockchain) {
+        if let Some(block) = blockchain.blocks.last() {
+            self.publish(Event::BlockConnected {
+                block: block.clone(),
+                height: blockchain.blocks.len() - 1,
+            });
+        }
+        self.tip_changed(blockchain);
+    }
+
+    /// Publishes the switch from `old` to `new` block by block.
+    pub fn chain_replaced(&self, old: &Blockchain, new: &Blockchain) {
+        let common = old
+            .blocks
+            .iter()
+            .zip(&new.blocks)
+            .take_while(|(a, b)| a.hash() == b.hash())
+            .count();
+        for (height, block) in old.blocks.iter().enumerate().skip(common).rev() {
+            self.publish(Event::BlockDisconnected {
+                block: block.clone(),
+                height,
+            });
+        }
+        for (height, block) in new.blocks.iter().enumerate().skip(common) {
+            self.publish(Event::BlockConnected {
+                block: block.clone(),
+                height,
+            });
+        }
+        self.tip_changed(new);
+    }
+
+    fn tip_changed(&self, blockchain: &Blockchain) {
+        if let Some(block) = blockchain.blocks.last() {
+            self.publish(Event::TipChanged {
+                hash: block.hash(),
+                height: blockchain.blocks.len() - 1,
+                weight: blockchain.weight,
+                difficulty: blockchain.cur_dif,
+            });
+        }
+    }
+}
+
+/// Keeps the most recent events, for consumers that look at them now and then.
+#[derive(Clone)]
+pub struct EventLog {
+    events: Arc<Mutex<VecDeque<Event>>>,
+}
+
+impl EventLog {
+    pub fn start(bus: &EventBus, capacity: usize) -> Self {
+        let events = Arc::new(Mutex::new(VecDeque::with_capacity(capacity)));
+        let mut receiver = bus.subscribe();
+
+        let events_copy = events.clone();
+        task::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => {
+                        let mut events = events_copy.lock().unwrap();
+                        if events.len() == capacity {
+                            events.pop_front();
+                        }
+                        events.push_back(event);
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+
+        Self { events }
+    }
+
+    /// Oldest first.
+    pub fn recent(&self) -> Vec<Event> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+}
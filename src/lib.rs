@@ -1,34 +1,63 @@
 pub type Hash = U256;
-pub const MINING_REQ: U256 = U256 {
-    0: [2 << 20, 0, 0, 0],
-};
+pub const MINING_REQ: U256 = U256([2 << 20, 0, 0, 0]);
 pub const MINING_REW: u64 = 100;
 pub const TIME_BASE: u64 = 30;
+pub const GENESIS_TIMESTAMP: u64 = 1_660_000_000;
+// Block timestamps have to be later than the median of this many previous blocks
+pub const MEDIAN_TIME_SPAN: usize = 11;
+// How far ahead of our clock block timestamps may be, in seconds
+pub const MAX_FUTURE_DRIFT: u64 = 2 * 60;
 
 pub mod blockchain;
 pub mod client;
+pub mod clock;
+pub mod compact;
+pub mod config;
+pub mod dashboard;
+pub mod events;
+pub mod logging;
+pub mod mempool;
+pub mod message;
+pub mod metrics;
 pub mod mining;
 pub mod node;
 pub mod p2p;
+pub mod peers;
+pub mod rpc;
+pub mod sim;
+pub mod storage;
+pub mod subscriptions;
+pub mod sync;
 pub mod transaction;
-
-use std::time::{SystemTime, UNIX_EPOCH};
+pub mod workers;
 
 pub use client::Client;
+pub use clock::{Clock, MockClock, NetworkClock, SystemClock};
+pub use config::Config;
+pub use dashboard::Dashboard;
+pub use events::{Event, EventBus, EventLog};
+pub use mempool::Mempool;
+pub use message::NetworkMessage;
+pub use metrics::{Metrics, MetricsServer};
+pub use mining::MiningConfig;
 pub use node::Node;
 
-pub use p2p::NetworkManager;
+pub use p2p::{NetworkConfig, NetworkManager, TransportKind};
+pub use rpc::{RpcClient, RpcServer};
+pub use subscriptions::SubscriptionServer;
 
 pub use async_std::{io, task};
 pub use blockchain::Block;
+pub use blockchain::BlockHeader;
 pub use blockchain::Blockchain;
+pub use blockchain::ChainSpec;
 pub use ethereum_types::U256;
 pub use futures::{
     prelude::{stream::StreamExt, *},
     select,
 };
 pub use libp2p::{
-    gossipsub::{self, Gossipsub, GossipsubConfig, GossipsubEvent},
+    gossipsub::{self, Gossipsub, GossipsubConfig, GossipsubEvent, MessageAcceptance, MessageId},
     identity,
     mdns::{Mdns, MdnsConfig, MdnsEvent},
     swarm::SwarmEvent,
@@ -41,13 +70,3 @@ pub use std::thread;
 pub use transaction::Transaction;
 
 pub use ed25519_dalek::{Keypair, PublicKey, Signature, Signer, Verifier};
-
-trait Hashable {
-    fn hash(&self) -> Hash;
-}
-
-pub fn now() -> u64 {
-    let duration = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-
-    duration.as_secs()
-}
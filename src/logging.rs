@@ -0,0 +1,28 @@
+use super::*;
+use std::fs;
+use std::path::Path;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::EnvFilter;
+
+// Everything this crate logs at info and above, only problems from libp2p
+pub const DEFAULT_LOG_FILTER: &str = "info,libp2p=warn";
+
+const LOG_FILE: &str = "node.log";
+
+/// Writes log records to a daily file in `dir`, keeping them off the terminal the dashboard runs in.
+/// `filter` takes `RUST_LOG` style directives per module, e.g. `info,blockchain_p2p::sync=debug`.
+/// Records are written on a background thread, the ones still queued are lost
+/// unless the returned guard is dropped before exiting.
+pub fn init(dir: &Path, filter: &str) -> Result<WorkerGuard, Box<dyn Error>> {
+    fs::create_dir_all(dir)?;
+    let (writer, guard) =
+        tracing_appender::non_blocking(tracing_appender::rolling::daily(dir, LOG_FILE));
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_new(filter)?)
+        .with_writer(writer)
+        .with_ansi(false)
+        .with_thread_names(true)
+        .try_init()
+        .map_err(|e| e.to_string())?;
+    Ok(guard)
+}
@@ -1,84 +1,307 @@
-use blockchain_p2p::{future::FusedFuture, *};
-use std::hash::Hash;
+use blockchain_p2p::*;
 
-use dialoguer::console::Term;
-use dialoguer::theme::ColorfulTheme;
-use dialoguer::Select;
+use clap::{Args, Parser, Subcommand};
+use serde_json::{json, Value};
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 
-const OPTIONS: [&str; 5] = [
-    "Current block",
-    "Balance",
-    "Blockchain",
-    "Make transaction",
-    "Exit",
-];
+/// A proof-of-work blockchain node, and a client for a running one.
+/// Settings are read from `config.toml` in the data directory, the options override them.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// Config file to read instead of the one in the data directory
+    #[arg(long, global = true, env = "BLOCKCHAIN_CONFIG")]
+    config: Option<PathBuf>,
+    /// Keys, chain, mempool, logs and the config file
+    #[arg(long, global = true, env = "BLOCKCHAIN_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+    /// JSON-RPC address the node serves on, and clients call
+    #[arg(long, global = true, env = "BLOCKCHAIN_RPC")]
+    rpc: Option<SocketAddr>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+// Everything but `node run` asks a running node over JSON-RPC and prints its answer as JSON
+#[derive(Subcommand)]
+enum Command {
+    /// Run a node
+    #[command(subcommand)]
+    Node(NodeCommand),
+    /// Use the node's wallet
+    #[command(subcommand)]
+    Wallet(WalletCommand),
+    /// Look at the active chain
+    #[command(subcommand)]
+    Chain(ChainCommand),
+    /// Look at pending transactions
+    #[command(subcommand)]
+    Mempool(MempoolCommand),
+}
+
+#[derive(Subcommand)]
+enum NodeCommand {
+    /// Runs a node, with a dashboard when started from a terminal
+    Run(Box<RunArgs>),
+    /// Connected peers with their scores
+    Peers,
+    /// Dials a peer and keeps redialing it like a bootstrap peer
+    AddPeer { address: Multiaddr },
+    /// Banned peers, when their bans expire and why
+    Banned,
+    /// Bans a peer, persistently unless a duration is given
+    Ban {
+        peer: String,
+        #[arg(long)]
+        seconds: Option<u64>,
+    },
+    /// Lifts a peer's ban
+    Unban { peer: String },
+}
+
+#[derive(Subcommand)]
+enum WalletCommand {
+    /// The node's wallet address
+    Address,
+    /// Coins held by an address, the node's wallet by default
+    Balance { address: Option<String> },
+    /// Sends coins from the node's wallet
+    Send { address: String, amount: u64 },
+}
+
+#[derive(Subcommand)]
+enum ChainCommand {
+    /// Tip, height, weight, difficulty and sync status of the active chain
+    Info,
+    /// A block of the active chain, by hash or height
+    Block { block: String },
+}
+
+#[derive(Subcommand)]
+enum MempoolCommand {
+    /// Transactions waiting to be mined
+    List,
+}
+
+#[derive(Args)]
+struct RunArgs {
+    /// Chain ID, only peers on the same network are talked to
+    #[arg(long, env = "BLOCKCHAIN_NETWORK")]
+    network: Option<String>,
+    /// Addresses to listen on, all interfaces on any port by default
+    #[arg(long, env = "BLOCKCHAIN_LISTEN", value_delimiter = ',')]
+    listen: Vec<Multiaddr>,
+    /// Addresses other peers should reach this node on
+    #[arg(long, env = "BLOCKCHAIN_ANNOUNCE", value_delimiter = ',')]
+    announce: Vec<Multiaddr>,
+    /// Peers to connect to, instead of the ones in the config file
+    #[arg(long, env = "BLOCKCHAIN_BOOTSTRAP", value_delimiter = ',')]
+    bootstrap: Vec<Multiaddr>,
+    /// Whether to start mining right away
+    #[arg(long)]
+    mining: Option<bool>,
+    /// Threads searching for a nonce
+    #[arg(long)]
+    mining_threads: Option<usize>,
+    /// Hex public key block rewards are paid to
+    #[arg(long)]
+    reward_address: Option<String>,
+    /// WebSocket subscription address
+    #[arg(long, env = "BLOCKCHAIN_WS")]
+    subscriptions: Option<SocketAddr>,
+    /// Prometheus metrics address
+    #[arg(long, env = "BLOCKCHAIN_METRICS")]
+    metrics: Option<SocketAddr>,
+    /// Log filter, e.g. `info,blockchain_p2p::sync=debug`
+    #[arg(long, env = "BLOCKCHAIN_LOG")]
+    log: Option<String>,
+    /// Where log files go, the data directory by default
+    #[arg(long, env = "BLOCKCHAIN_LOG_DIR")]
+    log_dir: Option<PathBuf>,
+}
+
+impl Cli {
+    fn config(&self) -> Result<Config, Box<dyn Error>> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::load_from_data_dir(
+                self.data_dir
+                    .as_deref()
+                    .unwrap_or(config::DEFAULT_DATA_DIR.as_ref()),
+            )?,
+        };
+        if let Some(data_dir) = &self.data_dir {
+            config.data_dir = data_dir.clone();
+        }
+        if let Some(rpc) = self.rpc {
+            config.rpc = rpc;
+        }
+        Ok(config)
+    }
+}
+
+impl RunArgs {
+    fn apply(self, config: &mut Config) {
+        if let Some(network) = self.network {
+            config.network = network;
+        }
+        if !self.listen.is_empty() {
+            config.listen = self.listen;
+        }
+        if !self.announce.is_empty() {
+            config.announce = self.announce;
+        }
+        if !self.bootstrap.is_empty() {
+            config.bootstrap = self.bootstrap;
+        }
+        if let Some(mining) = self.mining {
+            config.mining = mining;
+        }
+        if let Some(mining_threads) = self.mining_threads {
+            config.mining_threads = mining_threads;
+        }
+        if self.reward_address.is_some() {
+            config.reward_address = self.reward_address;
+        }
+        if let Some(subscriptions) = self.subscriptions {
+            config.subscriptions = subscriptions;
+        }
+        if let Some(metrics) = self.metrics {
+            config.metrics = metrics;
+        }
+        if let Some(log) = self.log {
+            config.log = log;
+        }
+        if self.log_dir.is_some() {
+            config.log_dir = self.log_dir;
+        }
+    }
+}
 
 #[async_std::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let blockchain_topic = gossipsub::IdentTopic::new("blockchain");
-    let transactions_topic = gossipsub::IdentTopic::new("transactions");
+    let cli = Cli::parse();
+    let mut config = cli.config()?;
+    let client = RpcClient::new(config.rpc);
+    let result = match cli.command {
+        Command::Node(NodeCommand::Run(args)) => {
+            args.apply(&mut config);
+            return run(config).await;
+        }
+        Command::Node(command) => node(&client, command),
+        Command::Wallet(command) => wallet(&client, command),
+        Command::Chain(command) => chain(&client, command),
+        Command::Mempool(MempoolCommand::List) => client.call("mempool", Value::Null),
+    };
+    match result {
+        Ok(result) => println!("{}", serde_json::to_string_pretty(&result)?),
+        // for scripts, the answer is all that goes to stdout
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
 
-    let mut client = Client::start(transactions_topic.clone()).await?;
+fn node(client: &RpcClient, command: NodeCommand) -> Result<Value, Box<dyn Error>> {
+    match command {
+        NodeCommand::Run(_) => unreachable!("runs the node instead of calling one"),
+        NodeCommand::Peers => client.call("peers", Value::Null),
+        NodeCommand::AddPeer { address } => client.call("add_peer", json!([address])),
+        NodeCommand::Banned => client.call("banned_peers", Value::Null),
+        NodeCommand::Ban { peer, seconds } => client.call("ban_peer", json!([peer, seconds])),
+        NodeCommand::Unban { peer } => client.call("unban_peer", json!([peer])),
+    }
+}
 
-    let node = Node::start(blockchain_topic, transactions_topic, client.key_pair.public).await?;
+fn wallet(client: &RpcClient, command: WalletCommand) -> Result<Value, Box<dyn Error>> {
+    match command {
+        WalletCommand::Address => client.call("wallet_address", Value::Null),
+        WalletCommand::Balance { address } => {
+            let address = match address {
+                Some(address) => address.into(),
+                None => client.call("wallet_address", Value::Null)?,
+            };
+            client.call("balance", json!([address]))
+        }
+        WalletCommand::Send { address, amount } => {
+            client.call("send_transaction", json!([address, amount]))
+        }
+    }
+}
+
+fn chain(client: &RpcClient, command: ChainCommand) -> Result<Value, Box<dyn Error>> {
+    match command {
+        ChainCommand::Info => client.call("chain_info", Value::Null),
+        ChainCommand::Block { block } => {
+            let result = match block.parse::<usize>() {
+                Ok(height) => client.call("block_by_height", json!([height]))?,
+                Err(_) => client.call("block_by_hash", json!([block]))?,
+            };
+            if result.is_null() {
+                return Err(format!("no block {} in the active chain", block).into());
+            }
+            Ok(result)
+        }
+    }
+}
+
+async fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    let network_config = config.network_config();
+
+    let _log_guard = logging::init(config.log_dir(), &config.log)?;
+    println!("Logging to {}", config.log_dir().display());
+
+    let blockchain_topic = network_config.topic("blockchain");
+    let transactions_topic = network_config.topic("transactions");
+
+    let key_pair = storage::load_wallet_key(&config.data_dir)?;
+    let reward_address = config.reward_address()?.unwrap_or(key_pair.public);
+
+    let node = Arc::new(
+        Node::start(
+            blockchain_topic,
+            transactions_topic,
+            reward_address,
+            config.mining_config(),
+            Arc::new(SystemClock),
+            &network_config,
+        )
+        .await?,
+    );
+    let client = Arc::new(Client::new(key_pair, &node));
+
+    let rpc_server = RpcServer::start(config.rpc, node.clone(), client.clone())?;
+    println!("JSON-RPC on http://{}", config.rpc);
+
+    let subscription_server = SubscriptionServer::start(config.subscriptions, node.events.clone())?;
+    println!("Subscriptions on ws://{}", config.subscriptions);
+
+    let metrics_server = MetricsServer::start(config.metrics, node.clone())?;
+    println!("Metrics on http://{}/metrics", config.metrics);
 
     println!("PUBLIC KEY: {}", hex::encode(client.key_pair.public));
 
-    loop {
-        let selection = Select::with_theme(&ColorfulTheme::default())
-            .with_prompt("Select action:")
-            .default(0)
-            .items(&OPTIONS)
-            .interact_on(&Term::stdout())
-            .unwrap();
-        match selection {
-            0 => println!("{:?}", node.active_block.lock().unwrap()),
-            1 => println!(
-                "{:?}",
-                node.active_blockchain
-                    .lock()
-                    .unwrap()
-                    .balances
-                    .get(client.key_pair.public.as_bytes())
-                    .unwrap_or(&0)
-            ),
-            2 => println!("{:?}", node.active_blockchain.lock().unwrap()),
-            3 => {
-                let payee_address =
-                    dialoguer::Input::<String>::with_theme(&ColorfulTheme::default())
-                        .with_prompt("Payee address")
-                        .interact_on(&Term::stdout())
-                        .unwrap();
-
-                let amount = dialoguer::Input::<u64>::with_theme(&ColorfulTheme::default())
-                    .with_prompt("Amount")
-                    .interact_on(&Term::stdout())
-                    .unwrap();
-
-                let balance = *node
-                    .active_blockchain
-                    .lock()
-                    .unwrap()
-                    .balances
-                    .get(client.key_pair.public.as_bytes())
-                    .unwrap_or(&0);
-
-                if balance < amount {
-                    println!("You don't have that many coins!");
-                    continue;
-                }
-
-                if let Ok(payee_address) = hex::decode(payee_address) {
-                    client.send_transaction(PublicKey::from_bytes(&payee_address).unwrap(), amount);
-                } else {
-                    println!("Wrong payee address format!");
-                }
-            }
+    // the dashboard reads Ctrl+C as a key, without one the handler tells us to shut down
+    let (interrupt, mut interrupted) = futures::channel::mpsc::unbounded();
+    ctrlc::set_handler(move || {
+        let _ = interrupt.unbounded_send(());
+    })?;
 
-            4 => break,
-            _ => println!("You need to select an action!"),
-        }
+    if std::io::stdin().is_terminal() {
+        Dashboard::new(node.clone(), client.clone()).run()?;
+    } else {
+        // nothing to ask for, runs until Ctrl+C
+        interrupted.next().await;
     }
 
+    println!("Shutting down...");
+    drop(metrics_server);
+    drop(subscription_server);
+    drop(rpc_server);
+    node.shutdown();
     Ok(())
 }
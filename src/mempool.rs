@@ -0,0 +1,192 @@
+use super::*;
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MempoolError {
+    InvalidSignature,
+    InsufficientBalance,
+}
+
+/// Valid transactions that haven't made it into the active chain yet, in the order they arrived.
+#[derive(Debug, Default)]
+pub struct Mempool {
+    transactions: Vec<Transaction>,
+    pending: HashSet<Hash>,
+    // transactions of the active chain, so they don't get back in when gossiped again
+    confirmed: HashSet<Hash>,
+}
+
+impl Mempool {
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.pending.contains(hash)
+    }
+
+    fn spendings(&self, user: &PublicKey) -> u64 {
+        self.transactions
+            .iter()
+            .filter(|t| &t.data.from == user)
+            .map(|t| t.data.amount)
+            .sum()
+    }
+
+    fn affordable(&self, blockchain: &Blockchain, transaction: &Transaction) -> bool {
+        let balance = blockchain
+            .balances
+            .get(transaction.data.from.as_bytes())
+            .unwrap_or(&0);
+        *balance >= self.spendings(&transaction.data.from) + transaction.data.amount
+    }
+
+    /// Adds a transaction if the sender can pay for it on top of what they already have pending,
+    /// returning whether it was new.
+    pub fn insert(
+        &mut self,
+        blockchain: &Blockchain,
+        transaction: Transaction,
+    ) -> Result<bool, MempoolError> {
+        let hash = transaction.hash();
+        if self.pending.contains(&hash) || self.confirmed.contains(&hash) {
+            return Ok(false);
+        }
+        if !transaction.valid() {
+            return Err(MempoolError::InvalidSignature);
+        }
+        if !self.affordable(blockchain, &transaction) {
+            return Err(MempoolError::InsufficientBalance);
+        }
+
+        self.pending.insert(hash);
+        self.transactions.push(transaction);
+        Ok(true)
+    }
+
+    /// Brings the pool in line with a new active chain, dropping the transactions it includes
+    /// and the ones their senders can't afford anymore.
+    pub fn update(&mut self, blockchain: &Blockchain) {
+        self.confirmed = blockchain
+            .blocks
+            .iter()
+            .flat_map(|b| &b.transactions)
+            .map(|t| t.hash())
+            .collect();
+
+        let transactions = std::mem::take(&mut self.transactions);
+        self.pending.clear();
+        for transaction in transactions {
+            let _ = self.insert(blockchain, transaction);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_pair() -> Keypair {
+        Keypair::generate(&mut rand::rngs::OsRng {})
+    }
+
+    fn mine(blockchain: &mut Blockchain, mut block: Block) {
+        let difficulty = blockchain.difficulty(&block.header);
+        while !mining::mined(&block.header, difficulty) {
+            block.header.nonce += 1;
+        }
+        blockchain.add_block(block).unwrap();
+    }
+
+    // a chain whose only block pays `payer` the mining reward
+    fn funded(payer: &Keypair) -> Blockchain {
+        let clock = Arc::new(MockClock::new(GENESIS_TIMESTAMP + TIME_BASE));
+        let mut blockchain = Blockchain::new(&ChainSpec::new("test"), clock);
+        let block = blockchain.generate_block(payer.public);
+        mine(&mut blockchain, block);
+        blockchain
+    }
+
+    #[test]
+    fn only_new_signed_transactions_get_in() {
+        let payer = key_pair();
+        let blockchain = funded(&payer);
+        let mut mempool = Mempool::default();
+
+        let transaction = Transaction::new(key_pair().public, 10, &payer);
+        assert_eq!(mempool.insert(&blockchain, transaction.clone()), Ok(true));
+        assert_eq!(mempool.insert(&blockchain, transaction.clone()), Ok(false));
+        assert!(mempool.contains(&transaction.hash()));
+
+        let mut forged = Transaction::new(key_pair().public, 10, &payer);
+        forged.data.amount = 20;
+        assert_eq!(
+            mempool.insert(&blockchain, forged),
+            Err(MempoolError::InvalidSignature)
+        );
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn pending_spends_count_against_the_balance() {
+        let payer = key_pair();
+        let blockchain = funded(&payer);
+        let mut mempool = Mempool::default();
+
+        let payee = key_pair().public;
+        assert_eq!(
+            mempool.insert(&blockchain, Transaction::new(payee, 60, &payer)),
+            Ok(true)
+        );
+        assert_eq!(
+            mempool.insert(&blockchain, Transaction::new(payee, 50, &payer)),
+            Err(MempoolError::InsufficientBalance)
+        );
+        assert_eq!(
+            mempool.insert(&blockchain, Transaction::new(payee, 40, &payer)),
+            Ok(true)
+        );
+
+        let broke = key_pair();
+        assert_eq!(
+            mempool.insert(&blockchain, Transaction::new(payee, 1, &broke)),
+            Err(MempoolError::InsufficientBalance)
+        );
+        assert_eq!(mempool.len(), 2);
+    }
+
+    #[test]
+    fn a_new_chain_evicts_confirmed_and_unaffordable_transactions() {
+        let payer = key_pair();
+        let mut blockchain = funded(&payer);
+        let mut mempool = Mempool::default();
+
+        let payee = key_pair().public;
+        let confirmed = Transaction::new(payee, 20, &payer);
+        let unaffordable = Transaction::new(payee, 60, &payer);
+        let affordable = Transaction::new(payee, 10, &payer);
+        for transaction in [&confirmed, &unaffordable, &affordable] {
+            assert_eq!(mempool.insert(&blockchain, transaction.clone()), Ok(true));
+        }
+
+        // the chain spends 70 of the payer's 100, 20 of it through a pooled transaction
+        let mut block = blockchain.generate_block(key_pair().public);
+        block.add_transaction(confirmed.clone());
+        block.add_transaction(Transaction::new(payee, 50, &payer));
+        mine(&mut blockchain, block);
+        mempool.update(&blockchain);
+
+        assert_eq!(mempool.transactions().len(), 1);
+        assert!(mempool.contains(&affordable.hash()));
+        // gossiped again, it stays out
+        assert_eq!(mempool.insert(&blockchain, confirmed), Ok(false));
+    }
+}
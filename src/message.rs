@@ -0,0 +1,142 @@
+use super::*;
+use crate::compact::CompactBlock;
+use crate::sync::{SyncRequest, SyncResponse};
+use std::fmt;
+
+// Bumped whenever the encoding of messages changes in a way older nodes can't read
+pub const MESSAGE_VERSION: u8 = 1;
+
+/// Optional protocol extensions, advertised in the header of every message so that peers
+/// can find out what the sender understands without a version bump.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Features(pub u32);
+
+impl Features {
+    pub const NONE: Features = Features(0);
+
+    pub fn contains(&self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+// What this node supports, extended as new features get added
+pub const LOCAL_FEATURES: Features = Features::NONE;
+
+/// Everything nodes send each other, on the gossip topics as well as over the sync protocol.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum NetworkMessage {
+    Blocks(Vec<Block>),
+    CompactBlock(Box<CompactBlock>),
+    Transaction(Box<Transaction>),
+    SyncRequest(SyncRequest),
+    SyncResponse(SyncResponse),
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Empty,
+    UnsupportedVersion(u8),
+    Malformed(bincode::Error),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Empty => write!(f, "empty message"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported message version {}", version)
+            }
+            DecodeError::Malformed(e) => write!(f, "malformed message: {}", e),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+impl NetworkMessage {
+    // The version byte comes first and on its own, so it can be read whatever follows it
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![MESSAGE_VERSION];
+        bincode::serialize_into(&mut data, &(LOCAL_FEATURES, self)).unwrap();
+        data
+    }
+
+    /// Decodes a message, returning it along with the features its sender supports.
+    pub fn decode(data: &[u8]) -> Result<(Features, NetworkMessage), DecodeError> {
+        let (&version, payload) = data.split_first().ok_or(DecodeError::Empty)?;
+        if version != MESSAGE_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        bincode::deserialize(payload).map_err(DecodeError::Malformed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction() -> Transaction {
+        let key_pair = Keypair::generate(&mut rand::rngs::OsRng {});
+        Transaction::new(key_pair.public, 42, &key_pair)
+    }
+
+    #[test]
+    fn messages_decode_to_what_was_encoded() {
+        let transaction = transaction();
+        let data = NetworkMessage::Transaction(Box::new(transaction.clone())).encode();
+        assert_eq!(data[0], MESSAGE_VERSION);
+
+        match NetworkMessage::decode(&data) {
+            Ok((features, NetworkMessage::Transaction(decoded))) => {
+                assert_eq!(features, LOCAL_FEATURES);
+                assert_eq!(decoded.hash(), transaction.hash());
+            }
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    fn other_versions_are_not_decoded() {
+        let mut data = NetworkMessage::Blocks(vec![]).encode();
+        data[0] = MESSAGE_VERSION + 1;
+        assert!(matches!(
+            NetworkMessage::decode(&data),
+            Err(DecodeError::UnsupportedVersion(version)) if version == MESSAGE_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn broken_messages_are_errors() {
+        assert!(matches!(
+            NetworkMessage::decode(&[]),
+            Err(DecodeError::Empty)
+        ));
+        assert!(matches!(
+            NetworkMessage::decode(&[MESSAGE_VERSION]),
+            Err(DecodeError::Malformed(_))
+        ));
+
+        let data = NetworkMessage::Transaction(Box::new(transaction())).encode();
+        assert!(matches!(
+            NetworkMessage::decode(&data[..data.len() - 1]),
+            Err(DecodeError::Malformed(_))
+        ));
+
+        // a variant this version doesn't know
+        let mut data = vec![MESSAGE_VERSION];
+        bincode::serialize_into(&mut data, &(LOCAL_FEATURES, 99u32)).unwrap();
+        assert!(matches!(
+            NetworkMessage::decode(&data),
+            Err(DecodeError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn features_contain_their_subsets() {
+        let features = Features(0b101);
+        assert!(features.contains(Features::NONE));
+        assert!(features.contains(Features(0b100)));
+        assert!(!features.contains(Features(0b010)));
+        assert!(!Features::NONE.contains(features));
+    }
+}
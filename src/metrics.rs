@@ -0,0 +1,210 @@
+use super::*;
+use crate::events::{Event, EventBus};
+use crate::peers::Misbehaviour;
+use prometheus_client::encoding::text::encode;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use std::net::SocketAddr;
+use tiny_http::{Header, Response, Server};
+use tokio::sync::broadcast;
+
+// Next to the JSON-RPC and subscription ports
+pub const DEFAULT_METRICS_ADDRESS: &str = "127.0.0.1:9547";
+
+type Labels = Vec<(&'static str, String)>;
+
+/// Counters and gauges for monitoring, in the OpenMetrics text format Prometheus scrapes.
+/// Chain, peer and reorg figures follow the event bus, the rest is counted where it happens.
+pub struct Metrics {
+    registry: Registry,
+    pub height: Gauge,
+    pub weight: Gauge,
+    pub difficulty: Gauge,
+    pub mempool: Gauge,
+    pub peers: Gauge,
+    // gossip messages, each of them gets validated once
+    pub messages_received: Counter,
+    // gossip and sync messages, by the misbehaviour or block validation error
+    pub messages_rejected: Family<Labels, Counter>,
+    // nonces tried, its rate is the hashrate
+    pub hashes: Counter,
+    pub reorgs: Counter,
+    pub blocks_disconnected: Counter,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let mut metrics = Self {
+            registry: Registry::with_prefix("blockchain"),
+            height: Default::default(),
+            weight: Default::default(),
+            difficulty: Default::default(),
+            mempool: Default::default(),
+            peers: Default::default(),
+            messages_received: Default::default(),
+            messages_rejected: Default::default(),
+            hashes: Default::default(),
+            reorgs: Default::default(),
+            blocks_disconnected: Default::default(),
+        };
+        let registry = &mut metrics.registry;
+        registry.register(
+            "height",
+            "Height of the active chain",
+            Box::new(metrics.height.clone()),
+        );
+        registry.register(
+            "weight",
+            "Accumulated difficulty of the active chain",
+            Box::new(metrics.weight.clone()),
+        );
+        registry.register(
+            "difficulty",
+            "Difficulty of the tip of the active chain",
+            Box::new(metrics.difficulty.clone()),
+        );
+        registry.register(
+            "mempool_transactions",
+            "Transactions waiting to be mined",
+            Box::new(metrics.mempool.clone()),
+        );
+        registry.register("peers", "Connected peers", Box::new(metrics.peers.clone()));
+        registry.register(
+            "messages_received",
+            "Gossip messages received",
+            Box::new(metrics.messages_received.clone()),
+        );
+        registry.register(
+            "messages_rejected",
+            "Messages rejected as invalid, by reason",
+            Box::new(metrics.messages_rejected.clone()),
+        );
+        registry.register(
+            "hashes",
+            "Nonces tried by the miner",
+            Box::new(metrics.hashes.clone()),
+        );
+        registry.register(
+            "reorgs",
+            "Times the active chain was replaced by a heavier fork",
+            Box::new(metrics.reorgs.clone()),
+        );
+        registry.register(
+            "blocks_disconnected",
+            "Blocks taken off the active chain by reorgs",
+            Box::new(metrics.blocks_disconnected.clone()),
+        );
+        metrics
+    }
+}
+
+impl Metrics {
+    /// Follows the chain through `events` from now on, starting at `blockchain`.
+    pub fn follow(self: &Arc<Self>, events: &EventBus, blockchain: &Blockchain) {
+        self.height
+            .set(blockchain.blocks.len().saturating_sub(1) as u64);
+        self.weight.set(blockchain.weight.into());
+        self.difficulty.set(blockchain.cur_dif.into());
+
+        let metrics = self.clone();
+        let mut receiver = events.subscribe();
+        task::spawn(async move {
+            // a reorg disconnects blocks in a row, up to the next tip change
+            let mut reorging = false;
+            loop {
+                match receiver.recv().await {
+                    Ok(Event::BlockDisconnected { .. }) => {
+                        if !reorging {
+                            metrics.reorgs.inc();
+                            reorging = true;
+                        }
+                        metrics.blocks_disconnected.inc();
+                    }
+                    Ok(Event::TipChanged {
+                        height,
+                        weight,
+                        difficulty,
+                        ..
+                    }) => {
+                        reorging = false;
+                        metrics.height.set(height as u64);
+                        metrics.weight.set(weight.into());
+                        metrics.difficulty.set(difficulty.into());
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    pub fn rejected(&self, misbehaviour: &Misbehaviour) {
+        let reason = match misbehaviour {
+            Misbehaviour::InvalidBlock(e) | Misbehaviour::InvalidHeaders(e) => format!("{:?}", e),
+            misbehaviour => format!("{:?}", misbehaviour),
+        };
+        self.messages_rejected
+            .get_or_create(&vec![("reason", reason)])
+            .inc();
+    }
+
+    pub fn encode(&self) -> String {
+        let mut buffer = vec![];
+        encode(&mut buffer, &self.registry).expect("writing to a Vec can't fail");
+        String::from_utf8(buffer).expect("metrics are UTF-8")
+    }
+}
+
+/// Serves the node's metrics over HTTP GET, on any path.
+pub struct MetricsServer {
+    server: Arc<Server>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    pub fn start(address: SocketAddr, node: Arc<Node>) -> Result<Self, Box<dyn Error>> {
+        let server = Arc::new(Server::http(address).map_err(|e| e.to_string())?);
+
+        let server_copy = server.clone();
+        let thread = thread::spawn(move || {
+            for request in server_copy.incoming_requests() {
+                // the mempool changes in too many places to follow, so it's looked at when scraped
+                let mempool = node.mempool.lock().unwrap().len();
+                node.metrics.mempool.set(mempool as u64);
+                // and the peer manager knows who's connected, events can be missed
+                let peers = node.peers.lock().unwrap().connected_peers().len();
+                node.metrics.peers.set(peers as u64);
+
+                let content_type = Header::from_bytes(
+                    "Content-Type",
+                    "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                )
+                .unwrap();
+                let _ = request.respond(
+                    Response::from_string(node.metrics.encode()).with_header(content_type),
+                );
+            }
+        });
+
+        Ok(Self {
+            server,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
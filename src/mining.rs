@@ -1,33 +1,125 @@
 use super::*;
 use crate::future::FusedFuture;
+use crate::workers::WorkerPool;
+use futures::task::AtomicWaker;
+use prometheus_client::metrics::counter::Counter;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
 use std::{thread, time::Duration};
+use tracing::{debug, info_span};
+
+// Threads sharing the search for a nonce
+pub const DEFAULT_MINING_THREADS: usize = 8;
+
+/// Whether the node mines from the start, it can be switched later, and on how many threads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MiningConfig {
+    pub enabled: bool,
+    pub threads: usize,
+}
+
+impl MiningConfig {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            threads: DEFAULT_MINING_THREADS,
+        }
+    }
+}
 
 pub struct BlockMiner {
     block: Arc<Mutex<Block>>,
     blockchain: Arc<Mutex<Blockchain>>,
+    clock: Arc<dyn Clock>,
+    // mining threads idle while it's false
+    pub enabled: Arc<AtomicBool>,
+    // and exit once it's true
+    stopped: Arc<AtomicBool>,
+    hashes: Counter,
+    // the task waiting for a sealed block, woken by the thread that sealed it
+    waker: Arc<AtomicWaker>,
+    workers: WorkerPool,
+    // disconnects once every mining thread has exited
+    finished: Option<mpsc::Receiver<()>>,
 }
 
 impl BlockMiner {
-    pub fn new(block: Arc<Mutex<Block>>, blockchain: Arc<Mutex<Blockchain>>) -> Self {
-        Self { block, blockchain }
+    pub fn new(
+        block: Arc<Mutex<Block>>,
+        blockchain: Arc<Mutex<Blockchain>>,
+        clock: Arc<dyn Clock>,
+        hashes: Counter,
+        config: MiningConfig,
+    ) -> Self {
+        Self {
+            block,
+            blockchain,
+            clock,
+            enabled: Arc::new(AtomicBool::new(config.enabled)),
+            stopped: Arc::new(AtomicBool::new(false)),
+            hashes,
+            waker: Default::default(),
+            // mining never finishes on its own, so nothing ever waits in line
+            workers: WorkerPool::new("miner", config.threads, config.threads),
+            finished: None,
+        }
+    }
+    pub fn start(&mut self) {
+        let block = self.block.clone();
+        let blockchain = self.blockchain.clone();
+        let clock = self.clock.clone();
+        let enabled = self.enabled.clone();
+        let stopped = self.stopped.clone();
+        let hashes = self.hashes.clone();
+        let waker = self.waker.clone();
+        let block_id = Arc::new(Mutex::new(0usize));
+        let sealed = Arc::new(Mutex::new(None));
+        let (finished, finished_receiver) = mpsc::channel::<()>();
+        self.workers.spawn_on_all(move || {
+            let _finished = finished.clone();
+            mine_on_thread(
+                &block,
+                &blockchain,
+                &*clock,
+                &enabled,
+                &stopped,
+                &hashes,
+                &waker,
+                &block_id,
+                &sealed,
+            );
+        });
+        self.finished = Some(finished_receiver);
     }
-    pub fn start(&self) {
-        let block_copy = self.block.clone();
-        let blockchain_copy = self.blockchain.clone();
-        thread::spawn(|| mine_block_multithreaded(block_copy, blockchain_copy));
+
+    /// Stops the mining threads and waits for them to exit.
+    pub fn stop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(finished) = self.finished.take() {
+            let _ = finished.recv();
+        }
     }
 }
 
 impl Future for BlockMiner {
     type Output = ();
 
-    fn poll(self: std::pin::Pin<&mut Self>, _: &mut task::Context<'_>) -> task::Poll<Self::Output> {
+    fn poll(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> task::Poll<Self::Output> {
+        // registered first, so a block sealed while this runs still wakes the task
+        self.waker.register(cx.waker());
+        // blocks can come out sealed at low difficulty, they only count while mining
+        if !self.enabled.load(Ordering::Relaxed) {
+            return task::Poll::Pending;
+        }
         let block = self.block.lock().unwrap();
         let blockchain = self.blockchain.lock().unwrap();
 
-        let difficulty = blockchain.difficulty(&block);
+        let difficulty = blockchain.difficulty(&block.header);
 
-        if mined(&block, difficulty) {
+        if mined(&block.header, difficulty) {
             task::Poll::Ready(())
         } else {
             task::Poll::Pending
@@ -41,70 +133,98 @@ impl FusedFuture for BlockMiner {
     }
 }
 
-pub fn mine_block_multithreaded(block: Arc<Mutex<Block>>, blockchain: Arc<Mutex<Blockchain>>) {
-    let block_id = Arc::new(Mutex::new(0usize));
-
-    thread::scope(|s| {
-        for _ in 0..8 {
-            let block_copy = block.clone();
-            let blockchain_copy = blockchain.clone();
-            let block_id_copy = block_id.clone();
-            s.spawn(move || {
-                let block = block_copy;
-                let blockchain = blockchain_copy;
-                let block_id = block_id_copy;
-
-                loop {
-                    let mining_block_id = block_id.lock().unwrap().clone();
-                    let active_block = block.lock().unwrap();
-                    let difficulty = (blockchain.lock().unwrap()).difficulty(&active_block);
-
-                    let mut mining_block = active_block.clone();
-                    drop(active_block);
-
-                    if mined(&mining_block, difficulty) {
-                        thread::sleep(std::time::Duration::from_millis(100))
-                    } else {
-                        mining_block.nonce = rand::random::<u64>() / 2;
-                        mining_block.timestamp = now();
-                        let difficulty = (blockchain.lock().unwrap()).difficulty(&mining_block);
-
-                        for _ in 0..100 {
-                            if mined(&mining_block, difficulty) {
-                                let mut block_id = block_id.lock().unwrap();
-                                if mining_block_id == *block_id {
-                                    *block_id += 1;
-                                    *block.lock().unwrap() = mining_block;
-                                }
-                                break;
-                            }
-                            mining_block.nonce += 1;
-                            thread::sleep_ms(1);
-                        }
+// One of the threads racing for the next block, they start over whenever one of them wins
+#[allow(clippy::too_many_arguments)]
+fn mine_on_thread(
+    block: &Mutex<Block>,
+    blockchain: &Mutex<Blockchain>,
+    clock: &dyn Clock,
+    enabled: &AtomicBool,
+    stopped: &AtomicBool,
+    hashes: &Counter,
+    waker: &AtomicWaker,
+    block_id: &Mutex<usize>,
+    // the last sealed block whose hash was counted
+    sealed: &Mutex<Option<Hash>>,
+) {
+    let _span = info_span!("mining").entered();
+    while !stopped.load(Ordering::Relaxed) {
+        if !enabled.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_millis(100));
+            continue;
+        }
+        let mining_block_id = *block_id.lock().unwrap();
+        let active_block = block.lock().unwrap();
+        let difficulty = (blockchain.lock().unwrap()).difficulty(&active_block.header);
+
+        let mut mining_block = active_block.clone();
+        drop(active_block);
+
+        if mined(&mining_block.header, difficulty) {
+            // a block can come out sealed at low difficulty, checking it was its one hash
+            let hash = mining_block.hash();
+            let mut sealed = sealed.lock().unwrap();
+            if *sealed != Some(hash) {
+                *sealed = Some(hash);
+                hashes.inc();
+            }
+            drop(sealed);
+            // still sealed, the node hasn't taken it yet
+            waker.wake();
+            thread::sleep(std::time::Duration::from_millis(100))
+        } else {
+            mining_block.header.nonce = rand::random::<u64>() / 2;
+            let blockchain = blockchain.lock().unwrap();
+            mining_block.header.timestamp = clock.now().max(blockchain.earliest_timestamp());
+            let difficulty = blockchain.difficulty(&mining_block.header);
+            drop(blockchain);
+
+            for _ in 0..100 {
+                if !enabled.load(Ordering::Relaxed) || stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                hashes.inc();
+                if mined(&mining_block.header, difficulty) {
+                    let mut block_id = block_id.lock().unwrap();
+                    if mining_block_id == *block_id {
+                        debug!(nonce = mining_block.header.nonce, "found a nonce");
+                        *block_id += 1;
+                        *sealed.lock().unwrap() = Some(mining_block.hash());
+                        *block.lock().unwrap() = mining_block;
+                        waker.wake();
                     }
+                    break;
                 }
-            });
+                mining_block.header.nonce += 1;
+                thread::sleep(Duration::from_millis(1));
+            }
         }
-    });
+    }
 }
 
-pub fn mine_block(block: Arc<Mutex<Block>>, blockchain: Arc<Mutex<Blockchain>>) {
+pub fn mine_block(
+    block: Arc<Mutex<Block>>,
+    blockchain: Arc<Mutex<Blockchain>>,
+    clock: Arc<dyn Clock>,
+) {
     loop {
         let mut mining_block = (block.lock().unwrap()).clone();
-        let difficulty = (blockchain.lock().unwrap()).difficulty(&mining_block);
-        if mined(&mining_block, difficulty) {
+        let difficulty = (blockchain.lock().unwrap()).difficulty(&mining_block.header);
+        if mined(&mining_block.header, difficulty) {
             thread::sleep(std::time::Duration::from_millis(10000));
         } else {
-            mining_block.nonce = rand::random::<u64>() / 2;
-            mining_block.timestamp = now();
-            let difficulty = (blockchain.lock().unwrap()).difficulty(&mining_block);
+            mining_block.header.nonce = rand::random::<u64>() / 2;
+            let blockchain = blockchain.lock().unwrap();
+            mining_block.header.timestamp = clock.now().max(blockchain.earliest_timestamp());
+            let difficulty = blockchain.difficulty(&mining_block.header);
+            drop(blockchain);
 
             for _ in 0..100 {
-                if mined(&mining_block, difficulty) {
+                if mined(&mining_block.header, difficulty) {
                     *block.lock().unwrap() = mining_block;
                     break;
                 }
-                mining_block.nonce += 1;
+                mining_block.header.nonce += 1;
 
                 // to decrease cpu consumption
                 thread::sleep(Duration::from_millis(10));
@@ -128,6 +248,6 @@ pub fn calculate_dif_offset(time_dif: u64) -> i32 {
     answer
 }
 
-pub fn mined(block: &Block, difficulty: u32) -> bool {
-    block.hash() % (2 << difficulty) == 0.into()
+pub fn mined(header: &BlockHeader, difficulty: u32) -> bool {
+    header.hash() % (2 << difficulty) == 0.into()
 }
@@ -1,9 +1,49 @@
 use super::*;
-use std::thread::{JoinHandle, Thread};
+use crate::blockchain::BlockValidationError;
+use crate::compact::{CompactBlock, CompactRelay, Reconstruction};
+use crate::events::{Event, EventBus};
+use crate::mempool::{Mempool, MempoolError};
+use crate::message::DecodeError;
+use crate::metrics::Metrics;
+use crate::peers::{Ban, Misbehaviour, PeerManager};
+use crate::storage;
+use crate::sync::{Status, SyncProgress, SyncRequest, SyncResponse, Synchronizer};
+use crate::workers::{self, WorkerPool};
+use futures::channel::{mpsc, oneshot};
+use futures_timer::Delay;
+use libp2p::request_response::{RequestResponseEvent, RequestResponseMessage};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::{debug, error, info, info_span, trace, warn};
+
+// Gossip messages waiting for or in validation, more than that are ignored
+const MAX_PENDING_VALIDATIONS: usize = 1024;
+
+// A compact block's message and the tip it was checked on, or why the block is invalid
+type CheckedBlock = (MessageId, PeerId, Result<Blockchain, BlockValidationError>);
+
+pub(crate) enum NodeCommand {
+    Dial(Multiaddr),
+    Ban(PeerId, Option<Duration>),
+    Unban(PeerId),
+    // answered with whether the mempool took it
+    SubmitTransaction(Box<Transaction>, oneshot::Sender<Result<(), MempoolError>>),
+    Shutdown,
+}
 
 pub struct Node {
+    pub peer_id: PeerId,
     pub active_blockchain: Arc<Mutex<Blockchain>>,
     pub active_block: Arc<Mutex<Block>>,
+    pub mempool: Arc<Mutex<Mempool>>,
+    pub sync_progress: Arc<Mutex<SyncProgress>>,
+    pub peers: Arc<Mutex<PeerManager>>,
+    pub events: EventBus,
+    pub metrics: Arc<Metrics>,
+    mining: Arc<AtomicBool>,
+    pub(crate) commands: mpsc::UnboundedSender<NodeCommand>,
+    // the event loop, taken when shutting down
+    task: Mutex<Option<task::JoinHandle<()>>>,
 }
 
 impl Node {
@@ -11,130 +51,506 @@ impl Node {
         blockchain_topic: gossipsub::IdentTopic,
         transaction_topic: gossipsub::IdentTopic,
         rew_pkey: PublicKey,
+        mining: mining::MiningConfig,
+        clock: Arc<dyn Clock>,
+        network_config: &p2p::NetworkConfig,
     ) -> Result<Self, Box<dyn Error>> {
-        let mut network_manager =
-            NetworkManager::start(vec![blockchain_topic.clone(), transaction_topic.clone()])
-                .await?;
+        let mut network_manager = NetworkManager::start(
+            vec![blockchain_topic.clone(), transaction_topic.clone()],
+            network_config,
+        )
+        .await?;
+        let peer_id = *network_manager.swarm.local_peer_id();
+
+        // block timestamps are judged by the time the network agrees on
+        let clock = Arc::new(NetworkClock::new(clock));
+        let mut blockchain = Blockchain::new(&network_config.chain, clock.clone());
+        let mut mempool = Mempool::default();
+        // picks up where the last run left off
+        let data_dir = network_config.data_dir.clone();
+        if let Some(data_dir) = &data_dir {
+            if let Some(saved_blockchain) = storage::load_chain(data_dir, &blockchain)? {
+                blockchain = saved_blockchain;
+            }
+            for transaction in storage::load_mempool(data_dir)? {
+                let _ = mempool.insert(&blockchain, transaction);
+            }
+            network_manager.restore_bans(storage::load_bans(data_dir)?);
+        }
+        let mut block = Block::default();
+        chain_changed(&blockchain, &mut block, &mut mempool, rew_pkey);
 
-        let active_blockchain = Arc::new(Mutex::new(Blockchain::default()));
-        let active_block = Arc::new(Mutex::new(
-            active_blockchain.lock().unwrap().generate_block(rew_pkey),
-        ));
+        let active_blockchain = Arc::new(Mutex::new(blockchain));
+        let active_block = Arc::new(Mutex::new(block));
+        let mempool = Arc::new(Mutex::new(mempool));
 
-        let mut block_miner =
-            mining::BlockMiner::new(active_block.clone(), active_blockchain.clone());
+        let mut block_miner = mining::BlockMiner::new(
+            active_block.clone(),
+            active_blockchain.clone(),
+            clock.clone(),
+            network_manager.metrics.hashes.clone(),
+            mining,
+        );
         block_miner.start();
+        let mining = block_miner.enabled.clone();
+
+        let mut synchronizer = Synchronizer::default();
+        let mut compact_relay = CompactRelay::default();
+        let sync_progress = synchronizer.progress.clone();
+
+        let peers = network_manager.peers.clone();
+        let events = EventBus::default();
+        let metrics = network_manager.metrics.clone();
+        metrics.follow(&events, &active_blockchain.lock().unwrap());
+        let (commands, mut commands_receiver) = mpsc::unbounded();
+        let (validations, mut validations_receiver) =
+            mpsc::unbounded::<(MessageId, PeerId, Result<MessageAcceptance, Misbehaviour>)>();
+        // blocks and chains validated on the workers, applied by the event loop
+        let (checked_blocks, mut checked_blocks_receiver) = mpsc::unbounded::<CheckedBlock>();
+        let (synced_chains, mut synced_chains_receiver) =
+            mpsc::unbounded::<Result<Blockchain, BlockValidationError>>();
+        let mut tick_timer = Delay::new(p2p::TICK_INTERVAL).fuse();
+        let validators = WorkerPool::new(
+            "validator",
+            workers::default_threads(),
+            MAX_PENDING_VALIDATIONS,
+        );
 
         let active_block_copy = active_block.clone();
         let active_blockchain_copy = active_blockchain.clone();
+        let mempool_copy = mempool.clone();
+        let events_copy = events.clone();
 
-        rayon::spawn(move || {
-            let rt = tokio::runtime::Runtime::new().unwrap();
-            rt.block_on(async {
-                let active_block = active_block_copy;
-                let active_blockchain = active_blockchain_copy;
-                loop {
-                    let active_block_copy = active_block.clone();
-                    let active_blockchain_copy = active_blockchain.clone();
-                    select! {
-                        _ = block_miner => {
-                            let mut block = active_block.lock().unwrap();
-                            let mut blockchain = active_blockchain.lock().unwrap();
-                            match blockchain.add_block(block.clone()) {
-                                Ok(()) => {
-//                                    println!("Sending to peers: {:?}.", blockchain);
-
-                                    network_manager.swarm.behaviour_mut().gossipsub
-                                    .publish(blockchain_topic.clone(), bincode::serialize(&blockchain.blocks).unwrap());
-                                }
-                                Err(e) => println!("Error encountered when adding mined block: {:?}", e)
+        // runs until the node shuts down, CPU-bound work goes to the worker pools
+        let task = task::spawn(async move {
+            let active_block = active_block_copy;
+            let active_blockchain = active_blockchain_copy;
+            let mempool = mempool_copy;
+            let events = events_copy;
+            loop {
+                let active_block_copy = active_block.clone();
+                let active_blockchain_copy = active_blockchain.clone();
+                let mempool_copy = mempool.clone();
+                let events_copy = events.clone();
+                select! {
+                    _ = block_miner => {
+                        let mut block = active_block.lock().unwrap();
+                        let mut blockchain = active_blockchain.lock().unwrap();
+                        match blockchain.add_block(block.clone()) {
+                            Ok(()) => {
+                                events.publish(Event::MiningSolved(block.clone()));
+                                info!(hash = %format_args!("{:x}", block.hash()), height = blockchain.blocks.len() - 1, "mined block");
+                                events.block_connected(&blockchain);
+
+                                // peers rebuild it from their mempools, the ones behind sync the chain
+                                let message = NetworkMessage::CompactBlock(Box::new(CompactBlock::new(&block)));
+                                let _ = network_manager.swarm.behaviour_mut().gossipsub
+                                .publish(blockchain_topic.clone(), message.encode());
+                            }
+                            Err(e) => warn!(error = ?e, "couldn't add mined block")
+                        }
+                        chain_changed(&blockchain, &mut block, &mut mempool.lock().unwrap(), rew_pkey);
+                    },
+                    _ = tick_timer => {
+                        network_manager.tick();
+                        tick_timer = Delay::new(p2p::TICK_INTERVAL).fuse();
+                    },
+                    command = commands_receiver.select_next_some() => match command {
+                        NodeCommand::Dial(address) => network_manager.add_peer(address),
+                        NodeCommand::Ban(peer, duration) => network_manager.ban_peer(peer, duration),
+                        NodeCommand::Unban(peer) => network_manager.unban_peer(peer),
+                        NodeCommand::Shutdown => break,
+                        // our own transactions go to the mempool first and only then out to the network
+                        NodeCommand::SubmitTransaction(transaction, reply) => {
+                            let message = NetworkMessage::Transaction(transaction.clone());
+                            let result = pool_transaction(&active_blockchain, &active_block, &mempool, &events, *transaction);
+                            if let Ok(true) = result {
+                                let _ = network_manager.swarm.behaviour_mut().gossipsub
+                                .publish(transaction_topic.clone(), message.encode());
                             }
-                            *block = blockchain.generate_block(rew_pkey);
-                        },
-                        event = network_manager.swarm.select_next_some() => match event {
+                            let _ = reply.send(result.map(|_| ()));
+                        }
+                    },
+                    (message_id, source, result) = validations_receiver.select_next_some() => {
+                        network_manager.validate(&message_id, &source, result);
+                    },
+                    (message_id, source, tip) = checked_blocks_receiver.select_next_some() => {
+                        let result = tip
+                            .map(|tip| connect_tip(&active_blockchain, &active_block, &mempool, &events, tip, rew_pkey))
+                            .map_err(Misbehaviour::InvalidBlock);
+                        network_manager.validate(&message_id, &source, result);
+                    },
+                    chain = synced_chains_receiver.select_next_some() => match chain {
+                        Ok(chain) => {
+                            adopt_chain(&active_blockchain, &active_block, &mempool, &events, chain, rew_pkey);
+                        }
+                        Err(e) => warn!(error = ?e, "downloaded chain is invalid"),
+                    },
+                    event = network_manager.swarm.select_next_some() => {
+                        let Some(event) = network_manager.handle_event(event) else {
+                            continue;
+                        };
+                        match event {
                             SwarmEvent::NewListenAddr { address, .. } => {
-                                //println!("Listening on {:?}", address);
+                                info!(%address, "listening");
                             }
-                            SwarmEvent::Behaviour(p2p::OutEvent::Gossipsub(
-                                libp2p::gossipsub::GossipsubEvent::Message{
-                                    propagation_source: _,
-                                    message_id: _,
-                                    message
+                            SwarmEvent::ConnectionEstablished { peer_id, num_established, .. }
+                                if num_established.get() == 1 =>
+                            {
+                                network_manager.peers.lock().unwrap().connected(peer_id);
+                                events.publish(Event::PeerConnected(peer_id));
+                                let status = Status::new(&active_blockchain.lock().unwrap());
+                                network_manager.swarm
+                                    .behaviour_mut()
+                                    .sync
+                                    .send_request(&peer_id, SyncRequest::Status(status));
+                            }
+                            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                                network_manager.peers.lock().unwrap().disconnected(&peer_id);
+                                clock.remove_sample(&peer_id);
+                                events.publish(Event::PeerDisconnected(peer_id));
+                            }
+                            SwarmEvent::Behaviour(p2p::OutEvent::Sync(RequestResponseEvent::Message {
+                                message: RequestResponseMessage::Response {
+                                    request_id,
+                                    response: SyncResponse::Transactions(transactions),
+                                },
+                                ..
+                            })) => {
+                                let sync = &mut network_manager.swarm.behaviour_mut().sync;
+                                if let Some((message_id, source, reconstruction)) = compact_relay.on_transactions(sync, request_id, transactions) {
+                                    if let Some(result) = reconstructed(&validators, &active_blockchain, &checked_blocks, message_id.clone(), source, reconstruction) {
+                                        network_manager.validate(&message_id, &source, result);
+                                    }
                                 }
-                            )) => {
-                                let topic = &message.topic;
-                                //println!("Message on {:?}.", topic);
-                                if topic == &blockchain_topic.hash() {
-                                    let rew_pkey = rew_pkey.clone();
-                                    thread::spawn(move || handle_blockchain(active_blockchain_copy, active_block_copy, &message.data, rew_pkey));
+                            }
+                            SwarmEvent::Behaviour(p2p::OutEvent::Sync(RequestResponseEvent::Message {
+                                message: RequestResponseMessage::Response {
+                                    request_id,
+                                    response: SyncResponse::Blocks(blocks),
+                                },
+                                ..
+                            })) if compact_relay.is_pending(&request_id) => {
+                                if let Some((message_id, source, reconstruction)) = compact_relay.on_blocks(request_id, blocks) {
+                                    if let Some(result) = reconstructed(&validators, &active_blockchain, &checked_blocks, message_id.clone(), source, reconstruction) {
+                                        network_manager.validate(&message_id, &source, result);
+                                    }
                                 }
-                                else if topic == &transaction_topic.hash() {
-                                    thread::spawn(move || handle_transaction(active_blockchain_copy, active_block_copy, &message.data));
+                            }
+                            SwarmEvent::Behaviour(p2p::OutEvent::Sync(RequestResponseEvent::OutboundFailure {
+                                request_id,
+                                ..
+                            })) if compact_relay.is_pending(&request_id) => {
+                                if let Some((message_id, source)) = compact_relay.on_failure(request_id) {
+                                    network_manager.validate(&message_id, &source, Ok(MessageAcceptance::Ignore));
                                 }
                             }
-                            SwarmEvent::Behaviour(p2p::OutEvent::Mdns(
-                                MdnsEvent::Discovered(list)
-                            )) => {
-                                //println!("NEW PEER DISCOVERED");
-                                for (peer, _) in list {
-                                    network_manager.swarm
-                                        .behaviour_mut()
-                                        .gossipsub
-                                        .add_explicit_peer(&peer);
+                            SwarmEvent::Behaviour(p2p::OutEvent::Sync(event)) => {
+                                let blocks = handle_sync(&mut network_manager, &mut synchronizer, &active_blockchain, &clock, event);
+                                // the whole chain is validated again, that's left to the validators
+                                if let Some(blocks) = blocks {
+                                    let chain = active_blockchain.lock().unwrap().genesis_chain();
+                                    let synced_chains = synced_chains.clone();
+                                    validators.spawn(move || {
+                                        let _ = synced_chains.unbounded_send(chain.validate_chain(blocks));
+                                    });
                                 }
                             }
-                            SwarmEvent::Behaviour(p2p::OutEvent::Mdns(MdnsEvent::Expired(
-                                list
-                            ))) => {
-                                //println!("PEER EXPIRED");
-                                for (peer, _) in list {
-                                    if !network_manager.swarm.behaviour_mut().mdns.has_node(&peer) {
-                                        network_manager.swarm
-                                            .behaviour_mut()
-                                            .gossipsub
-                                            .remove_explicit_peer(&peer);
+                            SwarmEvent::Behaviour(p2p::OutEvent::Gossipsub(
+                                libp2p::gossipsub::GossipsubEvent::Message{
+                                    propagation_source,
+                                    message_id,
+                                    message
+                                }
+                            )) => {
+                                let on_blockchain_topic = message.topic == blockchain_topic.hash();
+                                let on_transaction_topic = message.topic == transaction_topic.hash();
+                                trace!(topic = %message.topic, source = %propagation_source, "gossip message");
+                                // compact blocks may need more from the network, everything else is checked by the validators
+                                let decoded = match NetworkMessage::decode(&message.data) {
+                                    Ok((_, NetworkMessage::CompactBlock(compact))) if on_blockchain_topic => {
+                                        let reconstruction = compact_relay.on_compact_block(
+                                            &mut network_manager.swarm.behaviour_mut().sync,
+                                            &active_blockchain.lock().unwrap(),
+                                            &mempool.lock().unwrap(),
+                                            message_id.clone(),
+                                            propagation_source,
+                                            *compact,
+                                        );
+                                        if let Some(result) = reconstructed(&validators, &active_blockchain, &checked_blocks, message_id.clone(), propagation_source, reconstruction) {
+                                            network_manager.validate(&message_id, &propagation_source, result);
+                                        }
+                                        continue;
                                     }
+                                    decoded => decoded,
+                                };
+                                let validations = validations.clone();
+                                let (id, source) = (message_id.clone(), propagation_source);
+                                let queued = validators.try_spawn(move || {
+                                    let result = match decoded {
+                                        Ok((_, NetworkMessage::Blocks(blocks))) if on_blockchain_topic => {
+                                            handle_blockchain(active_blockchain_copy, active_block_copy, mempool_copy, &events_copy, blocks, rew_pkey)
+                                        }
+                                        Ok((_, NetworkMessage::Transaction(transaction))) if on_transaction_topic => {
+                                            handle_transaction(active_blockchain_copy, active_block_copy, mempool_copy, &events_copy, *transaction)
+                                        }
+                                        Ok(_) => Err(Misbehaviour::UnexpectedMessage),
+                                        // sent by a newer node, not necessarily invalid
+                                        Err(DecodeError::UnsupportedVersion(_)) => Ok(MessageAcceptance::Ignore),
+                                        Err(_) => Err(Misbehaviour::UndecodableMessage),
+                                    };
+                                    let _ = validations.unbounded_send((message_id, propagation_source, result));
+                                });
+                                // flooded, the message is dropped rather than queued without bound
+                                if !queued {
+                                    warn!(source = %source, "validators are busy, ignoring message");
+                                    network_manager.validate(&id, &source, Ok(MessageAcceptance::Ignore));
                                 }
-                            },
+                            }
                             _ => {}
                         }
                     }
                 }
-            });
+            }
+
+            info!("shutting down");
+            block_miner.stop();
+            // validations still running may change the chain and mempool
+            validators.drain();
+            if let Some(data_dir) = &data_dir {
+                let blockchain = active_blockchain.lock().unwrap();
+                let bans = network_manager.peers.lock().unwrap().banned_peers();
+                if let Err(e) =
+                    storage::save(data_dir, &blockchain, &mempool.lock().unwrap(), &bans)
+                {
+                    error!(error = %e, "couldn't save the chain");
+                }
+            }
+            network_manager.leave().await;
         });
 
         Ok(Self {
+            peer_id,
             active_blockchain,
             active_block,
+            mempool,
+            sync_progress,
+            peers,
+            events,
+            metrics,
+            mining,
+            commands,
+            task: Mutex::new(Some(task)),
         })
     }
+
+    /// Stops mining, waits for pending validations, saves the chain, mempool and bans to the data
+    /// directory, disconnects from peers and waits for all of it. The node does nothing afterwards.
+    pub fn shutdown(&self) {
+        let Some(task) = self.task.lock().unwrap().take() else {
+            return;
+        };
+        let _ = self.commands.unbounded_send(NodeCommand::Shutdown);
+        task::block_on(task);
+    }
+
+    pub fn is_mining(&self) -> bool {
+        self.mining.load(Ordering::Relaxed)
+    }
+
+    pub fn set_mining(&self, enabled: bool) {
+        self.mining.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn add_peer(&self, address: Multiaddr) {
+        let _ = self.commands.unbounded_send(NodeCommand::Dial(address));
+    }
+
+    /// Bans a peer for `duration`, or persistently when it's `None`.
+    pub fn ban_peer(&self, peer: PeerId, duration: Option<Duration>) {
+        let _ = self
+            .commands
+            .unbounded_send(NodeCommand::Ban(peer, duration));
+    }
+
+    pub fn unban_peer(&self, peer: PeerId) {
+        let _ = self.commands.unbounded_send(NodeCommand::Unban(peer));
+    }
+
+    pub fn banned_peers(&self) -> Vec<(PeerId, Ban)> {
+        self.peers.lock().unwrap().banned_peers()
+    }
+
+    pub fn connected_peers(&self) -> Vec<(PeerId, i32)> {
+        self.peers.lock().unwrap().connected_peers()
+    }
 }
 
-fn handle_blockchain(
-    active_blockchain: Arc<Mutex<Blockchain>>,
-    mining_block: Arc<Mutex<Block>>,
-    data: &[u8],
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// Starts a new mining block on top of the active chain with whatever is left in the mempool
+fn chain_changed(
+    active_blockchain: &Blockchain,
+    mining_block: &mut Block,
+    mempool: &mut Mempool,
     pub_key: PublicKey,
 ) {
+    mempool.update(active_blockchain);
+    *mining_block = active_blockchain.generate_block(pub_key);
+    for transaction in mempool.transactions() {
+        mining_block.add_transaction(transaction.clone());
+    }
+}
+
+// Checks a block extending the active chain on the validators against a tip of the chain,
+// the result comes back on `checked_blocks`. Returns the result right away when it isn't checked.
+// How to treat a compact block's announcement, none while its block is fetched or checked
+fn reconstructed(
+    validators: &WorkerPool,
+    active_blockchain: &Mutex<Blockchain>,
+    checked_blocks: &mpsc::UnboundedSender<CheckedBlock>,
+    message_id: MessageId,
+    source: PeerId,
+    reconstruction: Reconstruction,
+) -> Option<Result<MessageAcceptance, Misbehaviour>> {
+    match reconstruction {
+        Reconstruction::Complete(block) => check_block(
+            validators,
+            active_blockchain,
+            checked_blocks,
+            message_id,
+            source,
+            *block,
+        ),
+        Reconstruction::Pending => None,
+        Reconstruction::Done(result) => Some(result),
+    }
+}
+
+fn check_block(
+    validators: &WorkerPool,
+    active_blockchain: &Mutex<Blockchain>,
+    checked_blocks: &mpsc::UnboundedSender<CheckedBlock>,
+    message_id: MessageId,
+    source: PeerId,
+    block: Block,
+) -> Option<Result<MessageAcceptance, Misbehaviour>> {
+    let mut tip = {
+        let active_blockchain = active_blockchain.lock().unwrap();
+        if active_blockchain.blocks.last().map(|b| b.hash()) != Some(block.header.prev_hash) {
+            // our chain moved on while the block was being rebuilt
+            return Some(Ok(MessageAcceptance::Ignore));
+        }
+        active_blockchain.tip()
+    };
+    let checked_blocks = checked_blocks.clone();
+    let queued = validators.try_spawn(move || {
+        let result = tip.add_block(block).map(|()| tip);
+        let _ = checked_blocks.unbounded_send((message_id, source, result));
+    });
+    if queued {
+        None
+    } else {
+        warn!(source = %source, "validators are busy, ignoring block");
+        Some(Ok(MessageAcceptance::Ignore))
+    }
+}
+
+// Connects a block that was checked against `tip`
+fn connect_tip(
+    active_blockchain: &Mutex<Blockchain>,
+    mining_block: &Mutex<Block>,
+    mempool: &Mutex<Mempool>,
+    events: &EventBus,
+    tip: Blockchain,
+    pub_key: PublicKey,
+) -> MessageAcceptance {
     let mut mining_block = mining_block.lock().unwrap();
     let mut active_blockchain = active_blockchain.lock().unwrap();
 
-    //println!("Processing new blockchain.");
-
-    if let Ok(blocks) = bincode::deserialize::<Vec<Block>>(data) {
-        match Blockchain::construct(blocks) {
-            Ok(new_blockchain) => {
-                if new_blockchain.weight > active_blockchain.weight {
-                    *active_blockchain = new_blockchain;
-                    *mining_block = active_blockchain.generate_block(pub_key);
-                    //println!("{:?} accepted and replaced.", active_blockchain);
-                } else {
-                    //println!("Discarded blockchain, lighter than our own.");
-                }
-            }
-            Err(e) => {
-                //println!("Couldn't construct blockchain encountered: {:?}", e)
-            }
+    if !active_blockchain.connect_tip(tip) {
+        // our chain moved on while the block was being checked
+        return MessageAcceptance::Ignore;
+    }
+    events.block_connected(&active_blockchain);
+    chain_changed(
+        &active_blockchain,
+        &mut mining_block,
+        &mut mempool.lock().unwrap(),
+        pub_key,
+    );
+    MessageAcceptance::Accept
+}
+
+// Switches to `new_blockchain` if it's heavier than the active chain, returning whether it did
+fn adopt_chain(
+    active_blockchain: &Mutex<Blockchain>,
+    mining_block: &Mutex<Block>,
+    mempool: &Mutex<Mempool>,
+    events: &EventBus,
+    new_blockchain: Blockchain,
+    pub_key: PublicKey,
+) -> bool {
+    let mut mining_block = mining_block.lock().unwrap();
+    let mut active_blockchain = active_blockchain.lock().unwrap();
+
+    if !active_blockchain.prefers(&new_blockchain) {
+        debug!(
+            weight = new_blockchain.weight,
+            "discarded a chain lighter than ours"
+        );
+        return false;
+    }
+    let old_blockchain = std::mem::replace(&mut *active_blockchain, new_blockchain);
+    events.chain_replaced(&old_blockchain, &active_blockchain);
+    chain_changed(
+        &active_blockchain,
+        &mut mining_block,
+        &mut mempool.lock().unwrap(),
+        pub_key,
+    );
+    info!(
+        height = active_blockchain.blocks.len() - 1,
+        weight = active_blockchain.weight,
+        "switched to a heavier chain"
+    );
+    true
+}
+
+fn handle_blockchain(
+    active_blockchain: Arc<Mutex<Blockchain>>,
+    mining_block: Arc<Mutex<Block>>,
+    mempool: Arc<Mutex<Mempool>>,
+    events: &EventBus,
+    blocks: Vec<Block>,
+    pub_key: PublicKey,
+) -> Result<MessageAcceptance, Misbehaviour> {
+    let _span = info_span!("blockchain", blocks = blocks.len()).entered();
+    // rebuilt without holding the active chain, the event loop needs it meanwhile
+    let genesis_chain = active_blockchain.lock().unwrap().genesis_chain();
+    match genesis_chain.validate_chain(blocks) {
+        // valid, but there's no point in spreading a chain that lost
+        Ok(new_blockchain) => Ok(
+            if adopt_chain(
+                &active_blockchain,
+                &mining_block,
+                &mempool,
+                events,
+                new_blockchain,
+                pub_key,
+            ) {
+                MessageAcceptance::Accept
+            } else {
+                MessageAcceptance::Ignore
+            },
+        ),
+        Err(e) => {
+            debug!(error = ?e, "received an invalid chain");
+            Err(Misbehaviour::InvalidBlock(e))
         }
     }
 }
@@ -142,35 +558,133 @@ fn handle_blockchain(
 fn handle_transaction(
     active_blockchain: Arc<Mutex<Blockchain>>,
     mining_block: Arc<Mutex<Block>>,
-    data: &[u8],
-) {
-    let active_blockchain = active_blockchain.lock().unwrap();
+    mempool: Arc<Mutex<Mempool>>,
+    events: &EventBus,
+    transaction: Transaction,
+) -> Result<MessageAcceptance, Misbehaviour> {
+    match pool_transaction(
+        &active_blockchain,
+        &mining_block,
+        &mempool,
+        events,
+        transaction,
+    ) {
+        Ok(true) => Ok(MessageAcceptance::Accept),
+        Ok(false) => Ok(MessageAcceptance::Ignore),
+        Err(MempoolError::InsufficientBalance) => {
+            // the balance may differ on other chains, so don't punish the peer for it
+            Ok(MessageAcceptance::Ignore)
+        }
+        Err(MempoolError::InvalidSignature) => Err(Misbehaviour::InvalidTransactionSignature),
+    }
+}
+
+// Adds a transaction to the mempool and the block being mined, returning whether it was new
+fn pool_transaction(
+    active_blockchain: &Mutex<Blockchain>,
+    mining_block: &Mutex<Block>,
+    mempool: &Mutex<Mempool>,
+    events: &EventBus,
+    transaction: Transaction,
+) -> Result<bool, MempoolError> {
     let mut mining_block = mining_block.lock().unwrap();
-    let difficulty = active_blockchain.difficulty(&mining_block);
-    if mining::mined(&mining_block, difficulty) {
-        return;
-    }
-
-    if let Ok(transaction) = bincode::deserialize::<Transaction>(data) {
-        //println!("Processing {:?}", transaction);
-        if transaction.valid() {
-            let user_spendings = &mining_block.spendings(&transaction.data.from);
-            let user_balance = active_blockchain
-                .balances
-                .get(transaction.data.from.as_bytes())
-                .unwrap_or(&0);
-            if user_balance >= &(user_spendings + transaction.data.amount) {
-                mining_block.transactions.push(transaction);
-                //println!("Transaction successfully added to mining block");
-                //println!(
-                //    "Block now has {:?} transactions",
-                //    mining_block.transactions.len()
-                //);
-            } else {
-                println!("Not enough coins to make transaction");
+    let active_blockchain = active_blockchain.lock().unwrap();
+    let mut mempool = mempool.lock().unwrap();
+
+    let _span =
+        info_span!("transaction", hash = %format_args!("{:x}", transaction.hash())).entered();
+    let result = mempool.insert(&active_blockchain, transaction.clone());
+    if let Err(reason) = result {
+        events.publish(Event::TxRejected {
+            transaction: transaction.clone(),
+            reason,
+        });
+    }
+    match result {
+        Ok(true) => {
+            events.publish(Event::TxReceived(transaction.clone()));
+            // a sealed block keeps its transactions, this one goes into the next
+            let difficulty = active_blockchain.difficulty(&mining_block.header);
+            if !mining::mined(&mining_block.header, difficulty) {
+                mining_block.add_transaction(transaction);
+            }
+            debug!("added to the mempool");
+        }
+        Ok(false) => {}
+        Err(MempoolError::InsufficientBalance) => debug!("sender can't afford it"),
+        Err(MempoolError::InvalidSignature) => warn!("invalid signature"),
+    }
+    result
+}
+
+// Returns the blocks of a heavier chain once the synchronizer has downloaded all of them
+#[tracing::instrument(level = "debug", skip_all)]
+fn handle_sync(
+    network_manager: &mut NetworkManager,
+    synchronizer: &mut Synchronizer,
+    active_blockchain: &Mutex<Blockchain>,
+    clock: &NetworkClock,
+    event: RequestResponseEvent<SyncRequest, SyncResponse>,
+) -> Option<Vec<Block>> {
+    let peers: Vec<PeerId> = network_manager.swarm.connected_peers().copied().collect();
+    let sync = &mut network_manager.swarm.behaviour_mut().sync;
+
+    let blocks = match event {
+        RequestResponseEvent::Message {
+            peer,
+            message:
+                RequestResponseMessage::Request {
+                    request, channel, ..
+                },
+        } => {
+            let active_blockchain = active_blockchain.lock().unwrap();
+            if let SyncRequest::Status(status) = &request {
+                clock.add_sample(peer, status.timestamp);
+                synchronizer.on_status(sync, status, &active_blockchain, peers.iter());
             }
-        } else {
-            println!("Transaction has invalid signature");
+            let response = sync::respond(&active_blockchain, request);
+            let _ = sync.send_response(channel, response);
+            None
+        }
+        RequestResponseEvent::Message {
+            peer,
+            message:
+                RequestResponseMessage::Response {
+                    response: SyncResponse::Status(status),
+                    ..
+                },
+        } => {
+            clock.add_sample(peer, status.timestamp);
+            synchronizer.on_status(
+                sync,
+                &status,
+                &active_blockchain.lock().unwrap(),
+                peers.iter(),
+            );
+            None
+        }
+        RequestResponseEvent::Message {
+            message:
+                RequestResponseMessage::Response {
+                    request_id,
+                    response,
+                },
+            ..
+        } => synchronizer.on_response(
+            sync,
+            request_id,
+            response,
+            &active_blockchain.lock().unwrap(),
+        ),
+        RequestResponseEvent::OutboundFailure { request_id, .. } => {
+            synchronizer.on_failure(sync, request_id, &active_blockchain.lock().unwrap())
         }
+        _ => None,
     };
+
+    for (peer, misbehaviour) in std::mem::take(&mut synchronizer.misbehaviours) {
+        network_manager.report(peer, misbehaviour);
+    }
+
+    blocks
 }
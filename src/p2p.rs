@@ -1,21 +1,68 @@
 pub use libp2p::{
-    gossipsub::{self, Gossipsub, GossipsubConfig, GossipsubEvent, MessageAuthenticity},
+    core::{
+        muxing::StreamMuxerBox,
+        transport::{Boxed, ListenerId, MemoryTransport},
+        upgrade, ConnectedPoint, Transport,
+    },
+    gossipsub::{
+        self, Gossipsub, GossipsubConfigBuilder, GossipsubEvent, GossipsubMessage,
+        MessageAcceptance, MessageAuthenticity, MessageId, PeerScoreParams, PeerScoreThresholds,
+        ValidationMode,
+    },
+    identify::{Identify, IdentifyConfig, IdentifyEvent},
     identity,
     identity::Keypair,
+    kad::{store::MemoryStore, Kademlia, KademliaConfig, KademliaEvent},
     mdns::{Mdns, MdnsConfig, MdnsEvent},
-    swarm::SwarmEvent,
-    Multiaddr, NetworkBehaviour, PeerId, Swarm,
+    noise,
+    request_response::{RequestResponse, RequestResponseEvent},
+    swarm::{
+        behaviour::toggle::Toggle,
+        dial_opts::{DialOpts, PeerCondition},
+        AddressScore, SwarmEvent,
+    },
+    yamux, Multiaddr, NetworkBehaviour, PeerId, Swarm,
 };
 
 use super::*;
+use crate::metrics::Metrics;
+use crate::peers::{Ban, Misbehaviour, PeerManager};
+use crate::sync::{SyncCodec, SyncRequest, SyncResponse};
+use crypto_hash::{digest, Algorithm};
+use futures_timer::Delay;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
 
-// We create a custom network behaviour that combines floodsub and mDNS.
+// How often the network manager redials peers and refreshes the DHT
+pub const TICK_INTERVAL: Duration = Duration::from_secs(1);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+const BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(300);
+// How long leaving waits for peers to close their connections
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// File in the data directory holding the protobuf encoded network key
+const NETWORK_KEY_FILE: &str = "network_key";
+
+// Bumped whenever nodes running the previous version can't understand us anymore
+pub const PROTOCOL_VERSION: u32 = 5;
+
+// We create a custom network behaviour that combines gossipsub, mDNS, Kademlia and block sync.
 // Use the derive to generate delegating NetworkBehaviour impl.
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "OutEvent")]
 pub struct PeerBehaviour {
     pub gossipsub: Gossipsub,
-    pub mdns: Mdns,
+    // disabled on the memory transport
+    pub mdns: Toggle<Mdns>,
+    pub kademlia: Kademlia<MemoryStore>,
+    pub identify: Identify,
+    pub sync: RequestResponse<SyncCodec>,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -23,6 +70,21 @@ pub struct PeerBehaviour {
 pub enum OutEvent {
     Gossipsub(GossipsubEvent),
     Mdns(MdnsEvent),
+    Kademlia(KademliaEvent),
+    Identify(IdentifyEvent),
+    Sync(RequestResponseEvent<SyncRequest, SyncResponse>),
+}
+
+impl From<KademliaEvent> for OutEvent {
+    fn from(v: KademliaEvent) -> Self {
+        Self::Kademlia(v)
+    }
+}
+
+impl From<IdentifyEvent> for OutEvent {
+    fn from(v: IdentifyEvent) -> Self {
+        Self::Identify(v)
+    }
 }
 
 impl From<MdnsEvent> for OutEvent {
@@ -37,31 +99,179 @@ impl From<GossipsubEvent> for OutEvent {
     }
 }
 
+impl From<RequestResponseEvent<SyncRequest, SyncResponse>> for OutEvent {
+    fn from(v: RequestResponseEvent<SyncRequest, SyncResponse>) -> Self {
+        Self::Sync(v)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransportKind {
+    // TCP and WebSockets, with mDNS discovery
+    #[default]
+    Tcp,
+    // In process only, for running several nodes in one test without sockets.
+    // Listen addresses have to be given as /memory/<port>, peers only find each other
+    // through bootstrap peers.
+    Memory,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NetworkConfig {
+    pub transport: TransportKind,
+    pub chain: ChainSpec,
+    // Peers dialed at startup and redialed whenever the connection is lost
+    pub bootstrap_peers: Vec<Multiaddr>,
+    // Where the network key, chain and mempool are kept, a new identity is generated
+    // and the chain starts over on every start without it
+    pub data_dir: Option<PathBuf>,
+    // All IPv4 and IPv6 interfaces on a port the OS assigns when empty
+    pub listen_addresses: Vec<Multiaddr>,
+    // Addresses other peers should reach us on, e.g. behind NAT or port forwarding
+    pub announce_addresses: Vec<Multiaddr>,
+}
+
+impl NetworkConfig {
+    /// Gossip topics are namespaced per chain, so networks sharing a LAN don't mix.
+    pub fn topic(&self, name: &str) -> gossipsub::IdentTopic {
+        gossipsub::IdentTopic::new(format!("{}/{}", self.chain.chain_id, name))
+    }
+}
+
+/// What peers tell each other through identify, peers that don't match us get disconnected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub protocol_version: u32,
+    pub genesis_hash: Hash,
+    pub chain_id: String,
+}
+
+impl Handshake {
+    pub fn new(chain: &ChainSpec) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            genesis_hash: chain.genesis_hash(),
+            chain_id: chain.chain_id.clone(),
+        }
+    }
+
+    pub fn parse(protocol_version: &str) -> Option<Self> {
+        let mut parts = protocol_version.splitn(5, '/');
+        if parts.next() != Some("") || parts.next() != Some("blockchain") {
+            return None;
+        }
+        Some(Self {
+            protocol_version: parts.next()?.parse().ok()?,
+            genesis_hash: Hash::from_str_radix(parts.next()?, 16).ok()?,
+            chain_id: parts.next()?.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Handshake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "/blockchain/{}/{:x}/{}",
+            self.protocol_version, self.genesis_hash, self.chain_id
+        )
+    }
+}
+
+#[derive(Debug)]
+struct DialTarget {
+    address: Multiaddr,
+    peer_id: Option<PeerId>,
+    connected: bool,
+    next_attempt: Instant,
+    backoff: Duration,
+}
+
 pub struct NetworkManager {
     pub swarm: Swarm<PeerBehaviour>,
     pub local_key: Keypair,
-    pub topics: Vec<gossipsub::IdentTopic>
+    pub topics: Vec<gossipsub::IdentTopic>,
+    dial_targets: Vec<DialTarget>,
+    next_bootstrap: Instant,
+    pub peers: Arc<Mutex<PeerManager>>,
+    pub metrics: Arc<Metrics>,
+    handshake: Handshake,
+    kademlia_protocol: String,
+    // peers of other networks, ignored when rediscovered
+    foreign_peers: HashSet<PeerId>,
+    listeners: Vec<ListenerId>,
 }
 
 impl NetworkManager {
-    pub async fn start(topics: Vec<gossipsub::IdentTopic>) -> Result<Self, Box<dyn Error>> {
-        // Create a random PeerId
-        let local_key = identity::Keypair::generate_ed25519();
+    pub async fn start(
+        topics: Vec<gossipsub::IdentTopic>,
+        config: &NetworkConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        let local_key = match &config.data_dir {
+            Some(data_dir) => load_network_key(data_dir)?,
+            None => identity::Keypair::generate_ed25519(),
+        };
         let local_peer_id = PeerId::from(local_key.public());
-        println!("Local peer id: {:?}", local_peer_id);
+        info!(peer_id = %local_peer_id, "local peer id");
 
-        // Set up an encrypted DNS-enabled TCP Transport over the Mplex and Yamux protocols
-        let transport = libp2p::development_transport(local_key.clone()).await?;
+        let transport = match config.transport {
+            // Set up an encrypted DNS-enabled TCP Transport over the Mplex and Yamux protocols
+            TransportKind::Tcp => libp2p::development_transport(local_key.clone()).await?,
+            TransportKind::Memory => memory_transport(&local_key)?,
+        };
 
         let message_authenticity = MessageAuthenticity::Signed(local_key.clone());
 
+        let handshake = Handshake::new(&config.chain);
+        let kademlia_protocol = format!("/blockchain/{}/kad/1.0.0", config.chain.chain_id);
+
         // Create a Swarm to manage peers and events
         let mut swarm = {
-            let mdns = task::block_on(Mdns::new(MdnsConfig::default()))?;
-            let gossipsub_config = GossipsubConfig::default();
+            let mdns = match config.transport {
+                TransportKind::Tcp => Some(task::block_on(Mdns::new(MdnsConfig::default()))?),
+                TransportKind::Memory => None,
+            };
+            // messages are only forwarded once the node has validated them, and the same content
+            // published by different peers is only delivered once
+            let gossipsub_config = GossipsubConfigBuilder::default()
+                .validation_mode(ValidationMode::Strict)
+                .validate_messages()
+                .message_id_fn(|message: &GossipsubMessage| {
+                    MessageId::from(digest(Algorithm::SHA256, &message.data))
+                })
+                .build()?;
+
+            let mut kademlia_config = KademliaConfig::default();
+            kademlia_config.set_protocol_names(vec![kademlia_protocol.as_bytes().to_vec().into()]);
+            let kademlia = Kademlia::with_config(
+                local_peer_id,
+                MemoryStore::new(local_peer_id),
+                kademlia_config,
+            );
+
+            let identify = Identify::new(IdentifyConfig::new(
+                handshake.to_string(),
+                local_key.public(),
+            ));
+
+            // our own peer score is fed into gossipsub as the application specific score
+            let mut score_params = PeerScoreParams {
+                app_specific_weight: 1.0,
+                ..Default::default()
+            };
+            // so that many local nodes don't penalize each other
+            score_params
+                .ip_colocation_factor_whitelist
+                .insert(IpAddr::V4(Ipv4Addr::LOCALHOST));
+            let mut gossipsub = Gossipsub::new(message_authenticity, gossipsub_config)?;
+            gossipsub.with_peer_score(score_params, PeerScoreThresholds::default())?;
+
             let mut behaviour = PeerBehaviour {
-                gossipsub: Gossipsub::new(message_authenticity, gossipsub_config)?,
-                mdns,
+                gossipsub,
+                mdns: mdns.into(),
+                kademlia,
+                identify,
+                sync: sync::behaviour(&config.chain.chain_id),
             };
 
             for gossipsub_topic in &topics {
@@ -70,9 +280,421 @@ impl NetworkManager {
             Swarm::new(transport, behaviour, local_peer_id)
         };
 
-        // Listen on all interfaces and whatever port the OS assigns
-        swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+        let mut listeners = vec![];
+        if config.listen_addresses.is_empty() && config.transport == TransportKind::Tcp {
+            // Listen on all interfaces and whatever port the OS assigns
+            listeners.push(swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?);
+            // not every host has IPv6, so this one is best effort
+            match swarm.listen_on("/ip6/::/tcp/0".parse()?) {
+                Ok(listener) => listeners.push(listener),
+                Err(e) => warn!(error = %e, "not listening on IPv6"),
+            }
+        }
+        for address in &config.listen_addresses {
+            listeners.push(swarm.listen_on(address.clone())?);
+        }
+        for address in &config.announce_addresses {
+            swarm.add_external_address(address.clone(), AddressScore::Infinite);
+        }
+
+        let mut network_manager = Self {
+            swarm,
+            local_key,
+            topics,
+            dial_targets: vec![],
+            next_bootstrap: Instant::now(),
+            peers: Default::default(),
+            metrics: Default::default(),
+            handshake,
+            kademlia_protocol,
+            foreign_peers: HashSet::new(),
+            listeners,
+        };
+        for address in &config.bootstrap_peers {
+            network_manager.add_peer(address.clone());
+        }
+
+        Ok(network_manager)
+    }
+
+    /// Dials `address` now and keeps redialing it with backoff whenever it's not connected.
+    pub fn add_peer(&mut self, address: Multiaddr) {
+        if self.dial_targets.iter().all(|t| t.address != address) {
+            self.dial_targets.push(DialTarget {
+                address,
+                peer_id: None,
+                connected: false,
+                next_attempt: Instant::now(),
+                backoff: INITIAL_BACKOFF,
+            });
+        }
+        self.redial();
+    }
+
+    /// Redials bootstrap peers and refreshes the Kademlia routing table, should be called every `TICK_INTERVAL`.
+    pub fn tick(&mut self) {
+        self.redial();
+
+        let expired = self.peers.lock().unwrap().expire_bans();
+        for peer in expired {
+            self.unban_peer(peer);
+        }
+
+        if self.next_bootstrap <= Instant::now() {
+            // fails only while we don't know any peers yet
+            if self.swarm.behaviour_mut().kademlia.bootstrap().is_ok() {
+                self.next_bootstrap = Instant::now() + BOOTSTRAP_INTERVAL;
+            }
+        }
+    }
+
+    /// Stops listening and closes the connections to all peers, so they know we're gone
+    /// rather than timing out. Memory transport ports are only freed this way.
+    pub async fn leave(&mut self) {
+        for listener in self.listeners.drain(..) {
+            self.swarm.remove_listener(listener);
+        }
+        let peers: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+        for peer in peers {
+            let _ = self.swarm.disconnect_peer_id(peer);
+        }
+
+        let mut timeout = Delay::new(DISCONNECT_TIMEOUT).fuse();
+        while self.swarm.connected_peers().next().is_some() {
+            select! {
+                _ = self.swarm.select_next_some() => {},
+                _ = timeout => break,
+            }
+        }
+    }
+
+    /// Dials every disconnected peer whose backoff has expired.
+    pub fn redial(&mut self) {
+        let now = Instant::now();
+        for target in &mut self.dial_targets {
+            if target.connected || target.next_attempt > now {
+                continue;
+            }
+            if let Err(e) = self.swarm.dial(target.address.clone()) {
+                debug!(address = %target.address, error = ?e, "couldn't dial");
+            }
+            target.next_attempt = now + target.backoff;
+            target.backoff = (target.backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Penalizes a peer for sending invalid data, banning it once its score gets too low.
+    pub fn report(&mut self, peer: PeerId, misbehaviour: Misbehaviour) {
+        debug!(%peer, ?misbehaviour, "peer misbehaved");
+        self.metrics.rejected(&misbehaviour);
+        let (score, ban) = self.peers.lock().unwrap().report(peer, misbehaviour);
+        self.swarm
+            .behaviour_mut()
+            .gossipsub
+            .set_application_score(&peer, score as f64);
+        if let Some(ban) = &ban {
+            warn!(%peer, %ban, "peer banned");
+            self.swarm.ban_peer_id(peer);
+        }
+    }
+
+    /// Tells gossipsub whether to forward a message now that it has been checked,
+    /// penalizing the peer it came from if it turned out to be invalid.
+    pub fn validate(
+        &mut self,
+        message_id: &MessageId,
+        source: &PeerId,
+        result: Result<MessageAcceptance, Misbehaviour>,
+    ) {
+        self.metrics.messages_received.inc();
+        let acceptance = match result {
+            Ok(acceptance) => acceptance,
+            Err(misbehaviour) => {
+                self.report(*source, misbehaviour);
+                MessageAcceptance::Reject
+            }
+        };
+        let _ = self
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .report_message_validation_result(message_id, source, acceptance);
+    }
+
+    /// Bans a peer for `duration`, or persistently when it's `None`.
+    pub fn ban_peer(&mut self, peer: PeerId, duration: Option<Duration>) {
+        self.peers
+            .lock()
+            .unwrap()
+            .ban(peer, duration, "banned by operator".to_string());
+        self.swarm.ban_peer_id(peer);
+    }
+
+    /// Bans peers again after a restart, the ones whose bans ran out are let in on the next tick.
+    pub fn restore_bans(&mut self, bans: Vec<(PeerId, Ban)>) {
+        for (peer, ban) in bans {
+            self.peers.lock().unwrap().restore_ban(peer, ban);
+            self.swarm.ban_peer_id(peer);
+        }
+    }
+
+    pub fn unban_peer(&mut self, peer: PeerId) {
+        self.peers.lock().unwrap().unban(&peer);
+        self.swarm
+            .behaviour_mut()
+            .gossipsub
+            .set_application_score(&peer, 0.0);
+        self.swarm.unban_peer_id(peer);
+    }
+
+    fn disconnect_foreign(&mut self, peer: PeerId) {
+        self.foreign_peers.insert(peer);
+        self.swarm.behaviour_mut().kademlia.remove_peer(&peer);
+        let _ = self.swarm.disconnect_peer_id(peer);
+    }
+
+    // Connects to a discovered peer unless we already are, gossipsub then meshes with it
+    // like with any other peer, scoring it and pruning it when its score drops
+    fn dial_peer(&mut self, peer: PeerId, addresses: Vec<Multiaddr>) {
+        let dial = DialOpts::peer_id(peer)
+            .condition(PeerCondition::Disconnected)
+            .addresses(addresses)
+            .build();
+        if let Err(e) = self.swarm.dial(dial) {
+            debug!(%peer, error = ?e, "couldn't dial");
+        }
+    }
+
+    /// Handles peer discovery and keeps the state of dialed peers up to date, has to see every
+    /// swarm event. Events that are only relevant to the network manager are consumed.
+    pub fn handle_event<E>(
+        &mut self,
+        event: SwarmEvent<OutEvent, E>,
+    ) -> Option<SwarmEvent<OutEvent, E>> {
+        match event {
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                endpoint: ConnectedPoint::Dialer { ref address, .. },
+                ..
+            } => {
+                if let Some(target) = self.dial_targets.iter_mut().find(|t| &t.address == address) {
+                    target.peer_id = Some(peer_id);
+                    target.connected = true;
+                    target.backoff = INITIAL_BACKOFF;
+                    self.swarm
+                        .behaviour_mut()
+                        .kademlia
+                        .add_address(&peer_id, address.clone());
+                }
+                Some(event)
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } => {
+                for target in &mut self.dial_targets {
+                    if target.peer_id == Some(peer_id) {
+                        target.connected = false;
+                        target.next_attempt = Instant::now() + target.backoff;
+                    }
+                }
+                Some(event)
+            }
+            SwarmEvent::Behaviour(OutEvent::Mdns(MdnsEvent::Discovered(list))) => {
+                for (peer, address) in list {
+                    if self.foreign_peers.contains(&peer) {
+                        continue;
+                    }
+                    self.swarm
+                        .behaviour_mut()
+                        .kademlia
+                        .add_address(&peer, address.clone());
+                    self.dial_peer(peer, vec![address]);
+                }
+                None
+            }
+            SwarmEvent::Behaviour(OutEvent::Identify(IdentifyEvent::Received {
+                peer_id,
+                info,
+            })) => {
+                if Handshake::parse(&info.protocol_version).as_ref() != Some(&self.handshake) {
+                    self.disconnect_foreign(peer_id);
+                    return None;
+                }
+
+                // the listen addresses of peers that dialed us are only known through identify
+                if info.protocols.contains(&self.kademlia_protocol) {
+                    for address in info.listen_addrs {
+                        self.swarm
+                            .behaviour_mut()
+                            .kademlia
+                            .add_address(&peer_id, address);
+                    }
+                }
+                None
+            }
+            SwarmEvent::Behaviour(OutEvent::Kademlia(KademliaEvent::RoutingUpdated {
+                peer,
+                ..
+            })) => {
+                // peers found through the DHT join gossip the same way as mDNS peers
+                if !self.foreign_peers.contains(&peer) {
+                    self.dial_peer(peer, vec![]);
+                }
+                None
+            }
+            SwarmEvent::Behaviour(OutEvent::Identify(_) | OutEvent::Kademlia(_)) => None,
+            event => Some(event),
+        }
+    }
+}
 
-        Ok(Self { swarm, local_key, topics })
+fn memory_transport(
+    local_key: &Keypair,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>, Box<dyn Error>> {
+    let noise_keys = noise::Keypair::<noise::X25519Spec>::new().into_authentic(local_key)?;
+    Ok(MemoryTransport::default()
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
+        .multiplex(yamux::YamuxConfig::default())
+        .boxed())
+}
+
+/// Reads the network key from `data_dir`, generating and saving one on first start,
+/// so that the node keeps its peer id across restarts.
+fn load_network_key(data_dir: &Path) -> Result<Keypair, Box<dyn Error>> {
+    let path = data_dir.join(NETWORK_KEY_FILE);
+    if path.exists() {
+        return Ok(Keypair::from_protobuf_encoding(&fs::read(path)?)?);
+    }
+
+    let local_key = identity::Keypair::generate_ed25519();
+    fs::create_dir_all(data_dir)?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    // the key is all it takes to impersonate the node
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, &local_key.to_protobuf_encoding()?)?;
+    Ok(local_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshakes_survive_identify() {
+        let handshake = Handshake::new(&ChainSpec::new("test"));
+        assert_eq!(Handshake::parse(&handshake.to_string()), Some(handshake));
+
+        // the chain ID is the rest of the string
+        let handshake = Handshake::new(&ChainSpec::new("test/with/slashes"));
+        assert_eq!(Handshake::parse(&handshake.to_string()), Some(handshake));
+    }
+
+    #[test]
+    fn handshakes_tell_networks_apart() {
+        let test = Handshake::new(&ChainSpec::new("test"));
+        let other = Handshake::new(&ChainSpec::new("other"));
+        assert_ne!(test.genesis_hash, other.genesis_hash);
+        assert_ne!(Handshake::parse(&other.to_string()), Some(test.clone()));
+
+        let newer = Handshake {
+            protocol_version: PROTOCOL_VERSION + 1,
+            ..test.clone()
+        };
+        assert_ne!(Handshake::parse(&newer.to_string()), Some(test));
+    }
+
+    #[test]
+    fn other_protocol_versions_are_not_handshakes() {
+        let genesis_hash = format!("{:x}", ChainSpec::new("test").genesis_hash());
+        for protocol_version in [
+            String::new(),
+            "/ipfs/0.1.0".to_string(),
+            "blockchain/1".to_string(),
+            "/blockchain/1".to_string(),
+            format!("/blockchain/one/{}/test", genesis_hash),
+            "/blockchain/1/not-hex/test".to_string(),
+            format!("/blockchain/1/{}", genesis_hash),
+            format!("/chain/1/{}/test", genesis_hash),
+        ] {
+            assert_eq!(
+                Handshake::parse(&protocol_version),
+                None,
+                "{}",
+                protocol_version
+            );
+        }
+    }
+
+    fn memory_config(port: u64) -> NetworkConfig {
+        NetworkConfig {
+            transport: TransportKind::Memory,
+            chain: ChainSpec::new("test"),
+            listen_addresses: vec![format!("/memory/{}", port).parse().unwrap()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn the_peer_id_survives_restarts() {
+        let data_dir = std::env::temp_dir().join(format!("blockchain-p2p-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        let config = NetworkConfig {
+            data_dir: Some(data_dir.clone()),
+            ..memory_config(9101)
+        };
+
+        let first = task::block_on(NetworkManager::start(vec![], &config)).unwrap();
+        let peer_id = *first.swarm.local_peer_id();
+        drop(first);
+        // memory transport ports are only freed by leaving
+        let config = NetworkConfig {
+            listen_addresses: memory_config(9103).listen_addresses,
+            ..config
+        };
+        let second = task::block_on(NetworkManager::start(vec![], &config)).unwrap();
+        assert_eq!(*second.swarm.local_peer_id(), peer_id);
+
+        // without a data dir every start is a new identity
+        let config = memory_config(9102);
+        let other = task::block_on(NetworkManager::start(vec![], &config)).unwrap();
+        assert_ne!(*other.swarm.local_peer_id(), peer_id);
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn announced_addresses_are_sent_through_identify() {
+        let announced: Multiaddr = "/ip4/203.0.113.7/tcp/4001".parse().unwrap();
+        let config = NetworkConfig {
+            announce_addresses: vec![announced.clone()],
+            ..memory_config(9111)
+        };
+        let mut first = task::block_on(NetworkManager::start(vec![], &config)).unwrap();
+        let config = NetworkConfig {
+            bootstrap_peers: config.listen_addresses.clone(),
+            ..memory_config(9112)
+        };
+        let mut second = task::block_on(NetworkManager::start(vec![], &config)).unwrap();
+
+        let first_peer_id = *first.swarm.local_peer_id();
+        let listen_addrs = task::block_on(async {
+            loop {
+                select! {
+                    _ = first.swarm.select_next_some() => {}
+                    event = second.swarm.select_next_some() => {
+                        if let SwarmEvent::Behaviour(OutEvent::Identify(IdentifyEvent::Received { peer_id, info })) = event {
+                            if peer_id == first_peer_id {
+                                break info.listen_addrs;
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        assert!(listen_addrs.contains(&announced), "{:?}", listen_addrs);
     }
 }
@@ -0,0 +1,329 @@
+use super::*;
+use crate::blockchain::BlockValidationError;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::Duration;
+
+// Peers whose score drops to this value get banned
+pub const BAN_THRESHOLD: i32 = -100;
+// Every minute without misbehaving recovers one point of score
+const SCORE_RECOVERY: Duration = Duration::from_secs(60);
+// Temporary bans double in length with every ban, after that many the ban becomes persistent
+const BAN_DURATION: Duration = Duration::from_secs(60 * 60);
+const MAX_TEMPORARY_BANS: u32 = 3;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Misbehaviour {
+    UndecodableMessage,
+    UnexpectedMessage,
+    InvalidBlock(BlockValidationError),
+    InvalidTransactionSignature,
+    InvalidHeaders(BlockValidationError),
+    WrongBlockBodies,
+}
+
+impl Misbehaviour {
+    pub fn penalty(&self) -> i32 {
+        match self {
+            Misbehaviour::UndecodableMessage => 20,
+            Misbehaviour::UnexpectedMessage => 20,
+            Misbehaviour::InvalidBlock(BlockValidationError::NotMinedCorrectly) => 100,
+            // their clock may just be ahead, the block can become valid later
+            Misbehaviour::InvalidBlock(BlockValidationError::FutureTimestamp)
+            | Misbehaviour::InvalidHeaders(BlockValidationError::FutureTimestamp) => 0,
+            Misbehaviour::InvalidBlock(_) => 50,
+            Misbehaviour::InvalidTransactionSignature => 50,
+            Misbehaviour::InvalidHeaders(_) => 100,
+            Misbehaviour::WrongBlockBodies => 50,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ban {
+    // Seconds since the Unix epoch, None for persistent bans
+    pub until: Option<u64>,
+    pub reason: String,
+}
+
+impl Ban {
+    /// Seconds left at `now`, None for persistent bans.
+    pub fn expires_in(&self, now: u64) -> Option<u64> {
+        self.until.map(|until| until.saturating_sub(now))
+    }
+}
+
+impl fmt::Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.until {
+            Some(until) => write!(f, "banned until {}: {}", until, self.reason),
+            None => write!(f, "banned persistently: {}", self.reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PeerScore {
+    score: i32,
+    // when the score last changed
+    updated: u64,
+    bans: u32,
+}
+
+impl PeerScore {
+    fn new(now: u64) -> Self {
+        Self {
+            score: 0,
+            updated: now,
+            bans: 0,
+        }
+    }
+
+    fn recover(&mut self, now: u64) {
+        let recovered = (now.saturating_sub(self.updated) / SCORE_RECOVERY.as_secs()) as i32;
+        if recovered > 0 {
+            self.score = (self.score + recovered).min(0);
+            self.updated = now;
+        }
+    }
+}
+
+/// Keeps track of how peers behave and which of them are banned.
+pub struct PeerManager {
+    clock: Arc<dyn Clock>,
+    scores: HashMap<PeerId, PeerScore>,
+    bans: HashMap<PeerId, Ban>,
+    connected: HashSet<PeerId>,
+}
+
+impl Default for PeerManager {
+    fn default() -> Self {
+        Self::new(clock::system())
+    }
+}
+
+impl PeerManager {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            scores: HashMap::new(),
+            bans: HashMap::new(),
+            connected: HashSet::new(),
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    pub fn score(&self, peer: &PeerId) -> i32 {
+        self.scores.get(peer).map(|s| s.score).unwrap_or(0)
+    }
+
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.bans.contains_key(peer)
+    }
+
+    pub fn banned_peers(&self) -> Vec<(PeerId, Ban)> {
+        self.bans.iter().map(|(p, b)| (*p, b.clone())).collect()
+    }
+
+    pub fn connected(&mut self, peer: PeerId) {
+        self.connected.insert(peer);
+    }
+
+    pub fn disconnected(&mut self, peer: &PeerId) {
+        self.connected.remove(peer);
+    }
+
+    /// The peers we have a connection to, with their scores.
+    pub fn connected_peers(&self) -> Vec<(PeerId, i32)> {
+        let mut peers: Vec<_> = self
+            .connected
+            .iter()
+            .map(|peer| (*peer, self.score(peer)))
+            .collect();
+        peers.sort();
+        peers
+    }
+
+    /// Penalizes `peer`, returning its new score and the ban if it crossed `BAN_THRESHOLD`.
+    pub fn report(&mut self, peer: PeerId, misbehaviour: Misbehaviour) -> (i32, Option<Ban>) {
+        let now = self.clock.now();
+        let score = self
+            .scores
+            .entry(peer)
+            .or_insert_with(|| PeerScore::new(now));
+        score.recover(now);
+        score.score -= misbehaviour.penalty();
+
+        if score.score > BAN_THRESHOLD || self.bans.contains_key(&peer) {
+            return (score.score, None);
+        }
+
+        score.bans += 1;
+        let duration = if score.bans > MAX_TEMPORARY_BANS {
+            None
+        } else {
+            Some(BAN_DURATION * 2u32.pow(score.bans - 1))
+        };
+        let score = score.score;
+
+        (
+            score,
+            Some(self.ban(peer, duration, format!("{:?}", misbehaviour))),
+        )
+    }
+
+    /// Bans `peer` for `duration`, or persistently when it's `None`.
+    pub fn ban(&mut self, peer: PeerId, duration: Option<Duration>, reason: String) -> Ban {
+        let until = duration.map(|d| self.clock.now() + d.as_secs());
+        let ban = Ban { until, reason };
+        self.bans.insert(peer, ban.clone());
+        ban
+    }
+
+    /// Puts back a ban from before a restart.
+    pub fn restore_ban(&mut self, peer: PeerId, ban: Ban) {
+        self.bans.insert(peer, ban);
+    }
+
+    /// Lifts the ban and forgives the peer, returning whether it was banned.
+    pub fn unban(&mut self, peer: &PeerId) -> bool {
+        if let Some(score) = self.scores.get_mut(peer) {
+            score.score = 0;
+        }
+        self.bans.remove(peer).is_some()
+    }
+
+    /// Removes temporary bans that have run out and returns the peers they applied to.
+    pub fn expire_bans(&mut self) -> Vec<PeerId> {
+        let now = self.clock.now();
+        let expired: Vec<PeerId> = self
+            .bans
+            .iter()
+            .filter(|(_, ban)| matches!(ban.until, Some(until) if until <= now))
+            .map(|(peer, _)| *peer)
+            .collect();
+
+        for peer in &expired {
+            self.unban(peer);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager() -> (Arc<MockClock>, PeerManager) {
+        let clock = Arc::new(MockClock::new(1_000_000));
+        (clock.clone(), PeerManager::new(clock))
+    }
+
+    #[test]
+    fn peers_are_banned_once_their_score_reaches_the_threshold() {
+        let (_, mut peers) = manager();
+        let peer = PeerId::random();
+
+        for _ in 0..4 {
+            assert_eq!(peers.report(peer, Misbehaviour::UnexpectedMessage).1, None);
+        }
+        assert_eq!(peers.score(&peer), -80);
+        assert!(!peers.is_banned(&peer));
+
+        let (score, ban) = peers.report(peer, Misbehaviour::UnexpectedMessage);
+        assert_eq!(score, BAN_THRESHOLD);
+        assert_eq!(ban.unwrap().reason, "UnexpectedMessage");
+        assert!(peers.is_banned(&peer));
+    }
+
+    #[test]
+    fn future_timestamps_are_not_penalized() {
+        let (_, mut peers) = manager();
+        let peer = PeerId::random();
+        let misbehaviour = Misbehaviour::InvalidBlock(BlockValidationError::FutureTimestamp);
+
+        assert_eq!(peers.report(peer, misbehaviour), (0, None));
+    }
+
+    #[test]
+    fn scores_recover_over_time() {
+        let (clock, mut peers) = manager();
+        let peer = PeerId::random();
+        peers.report(
+            peer,
+            Misbehaviour::InvalidBlock(BlockValidationError::PrevHashMismatch),
+        );
+        assert_eq!(peers.score(&peer), -50);
+
+        // 30 points back, then the penalty
+        clock.advance(30 * SCORE_RECOVERY.as_secs());
+        assert_eq!(peers.report(peer, Misbehaviour::UnexpectedMessage).0, -40);
+
+        // never above 0
+        clock.advance(1000 * SCORE_RECOVERY.as_secs());
+        assert_eq!(peers.report(peer, Misbehaviour::UnexpectedMessage).0, -20);
+    }
+
+    #[test]
+    fn temporary_bans_expire_and_grow_until_they_become_persistent() {
+        let (clock, mut peers) = manager();
+        let peer = PeerId::random();
+
+        for bans in 0..MAX_TEMPORARY_BANS {
+            let duration = BAN_DURATION.as_secs() * 2u64.pow(bans);
+            let ban = peers
+                .report(
+                    peer,
+                    Misbehaviour::InvalidHeaders(BlockValidationError::PrevHashMismatch),
+                )
+                .1
+                .unwrap();
+            assert_eq!(ban.expires_in(clock.now()), Some(duration));
+
+            clock.advance(duration - 1);
+            assert!(peers.expire_bans().is_empty());
+            clock.advance(1);
+            assert_eq!(peers.expire_bans(), vec![peer]);
+            assert!(!peers.is_banned(&peer));
+            assert_eq!(peers.score(&peer), 0);
+        }
+
+        let ban = peers
+            .report(
+                peer,
+                Misbehaviour::InvalidHeaders(BlockValidationError::PrevHashMismatch),
+            )
+            .1
+            .unwrap();
+        assert_eq!(ban.until, None);
+        clock.advance(u32::MAX as u64);
+        assert!(peers.expire_bans().is_empty());
+        assert!(peers.is_banned(&peer));
+    }
+
+    #[test]
+    fn unbanning_forgives_the_peer() {
+        let (clock, mut peers) = manager();
+        let peer = PeerId::random();
+        assert!(!peers.unban(&peer));
+
+        peers.report(
+            peer,
+            Misbehaviour::InvalidHeaders(BlockValidationError::PrevHashMismatch),
+        );
+        assert!(peers.is_banned(&peer));
+        assert!(peers.unban(&peer));
+        assert!(!peers.is_banned(&peer));
+        assert_eq!(peers.score(&peer), 0);
+        assert!(peers.banned_peers().is_empty());
+
+        let ban = peers.ban(peer, None, "banned by operator".to_string());
+        assert_eq!(peers.banned_peers(), vec![(peer, ban)]);
+        clock.advance(BAN_DURATION.as_secs());
+        assert!(peers.expire_bans().is_empty());
+        assert!(peers.unban(&peer));
+    }
+}
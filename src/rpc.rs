@@ -0,0 +1,352 @@
+use super::*;
+use crate::client::SendError;
+use crate::events::{Event, EventLog};
+use crate::mempool::MempoolError;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use tiny_http::{Header, Method, Response, Server};
+
+// Localhost only, anyone reaching the server can spend the wallet's coins
+pub const DEFAULT_RPC_ADDRESS: &str = "127.0.0.1:9545";
+
+// How many of the latest node events `recent_events` returns
+const RECENT_EVENTS: usize = 100;
+
+// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// understood, but the node won't do it
+const REJECTED: i64 = -32000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: &str) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl Error for RpcError {}
+
+#[derive(Deserialize)]
+struct Request {
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// JSON-RPC 2.0 over HTTP POST, answering from the node's state and sending through its wallet.
+/// Params are positional, hashes and addresses are hex strings.
+pub struct RpcServer {
+    server: Arc<Server>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl RpcServer {
+    pub fn start(
+        address: SocketAddr,
+        node: Arc<Node>,
+        wallet: Arc<Client>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let server = Arc::new(Server::http(address).map_err(|e| e.to_string())?);
+        let handler = Handler {
+            events: EventLog::start(&node.events, RECENT_EVENTS),
+            node,
+            wallet,
+        };
+
+        let server_copy = server.clone();
+        let thread = thread::spawn(move || {
+            for mut request in server_copy.incoming_requests() {
+                if *request.method() != Method::Post {
+                    let _ = request.respond(Response::empty(405));
+                    continue;
+                }
+                let mut body = String::new();
+                let response = match request.as_reader().read_to_string(&mut body) {
+                    Ok(_) => handler.handle(&body),
+                    Err(_) => error(Value::Null, RpcError::new(PARSE_ERROR, "Unreadable body")),
+                };
+                let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+                let _ = request
+                    .respond(Response::from_string(response.to_string()).with_header(content_type));
+            }
+        });
+
+        Ok(Self {
+            server,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+}
+
+impl Drop for RpcServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Reply {
+    result: Option<Value>,
+    error: Option<RpcError>,
+}
+
+/// Makes calls to a node's JSON-RPC server, one connection each.
+pub struct RpcClient {
+    address: SocketAddr,
+}
+
+impl RpcClient {
+    pub fn new(address: SocketAddr) -> Self {
+        Self { address }
+    }
+
+    /// The result of calling `method`, an error response comes back as an `RpcError`.
+    pub fn call(&self, method: &str, params: Value) -> Result<Value, Box<dyn Error>> {
+        let body =
+            json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 }).to_string();
+        let mut stream = TcpStream::connect(self.address)
+            .map_err(|e| format!("can't reach the node at {}: {}", self.address, e))?;
+        // a bare HTTP/1.1 request, the server answers with a Content-Length and closes
+        write!(
+            stream,
+            "POST / HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.address,
+            body.len(),
+            body
+        )?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let (_, body) = response
+            .split_once("\r\n\r\n")
+            .ok_or("malformed HTTP response")?;
+        let response: Reply = serde_json::from_str(body)?;
+        match response.error {
+            Some(error) => Err(error.into()),
+            None => Ok(response.result.unwrap_or(Value::Null)),
+        }
+    }
+}
+
+struct Handler {
+    node: Arc<Node>,
+    wallet: Arc<Client>,
+    events: EventLog,
+}
+
+impl Handler {
+    /// Answers a request body, a single call or a batch of them.
+    fn handle(&self, body: &str) -> Value {
+        match serde_json::from_str(body) {
+            Ok(Value::Array(batch)) if !batch.is_empty() => {
+                Value::Array(batch.into_iter().map(|r| self.call(r)).collect())
+            }
+            Ok(request) => self.call(request),
+            Err(_) => error(Value::Null, RpcError::new(PARSE_ERROR, "Parse error")),
+        }
+    }
+
+    fn call(&self, request: Value) -> Value {
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let result = match serde_json::from_value::<Request>(request) {
+            Ok(request) => self.dispatch(&request.method, request.params),
+            Err(_) => Err(RpcError::new(INVALID_REQUEST, "Invalid request")),
+        };
+        match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err(e) => error(id, e),
+        }
+    }
+
+    fn dispatch(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        let (node, wallet) = (&self.node, &self.wallet);
+        match method {
+            "chain_info" => {
+                let blockchain = node.active_blockchain.lock().unwrap();
+                Ok(json!({
+                    "genesis": blockchain.genesis_hash().map(hex_hash),
+                    "tip": blockchain.blocks.last().map(|b| hex_hash(b.hash())),
+                    // the genesis block is at height 0
+                    "height": blockchain.blocks.len() - 1,
+                    "weight": blockchain.weight,
+                    "difficulty": blockchain.cur_dif,
+                    "sync": node.sync_progress.lock().unwrap().to_string(),
+                }))
+            }
+            "block_by_hash" => {
+                let (hash,): (String,) = parse_params(params)?;
+                let hash = parse_hash(&hash)?;
+                let blockchain = node.active_blockchain.lock().unwrap();
+                let block = blockchain.blocks.iter().rev().find(|b| b.hash() == hash);
+                Ok(block.map_or(Value::Null, block_json))
+            }
+            "block_by_height" => {
+                let (height,): (usize,) = parse_params(params)?;
+                let blockchain = node.active_blockchain.lock().unwrap();
+                Ok(blockchain
+                    .blocks
+                    .get(height)
+                    .map_or(Value::Null, block_json))
+            }
+            "balance" => {
+                let (address,): (String,) = parse_params(params)?;
+                let address = parse_address(&address)?;
+                let blockchain = node.active_blockchain.lock().unwrap();
+                Ok(json!(blockchain
+                    .balances
+                    .get(address.as_bytes())
+                    .unwrap_or(&0)))
+            }
+            "mempool" => {
+                let mempool = node.mempool.lock().unwrap();
+                Ok(mempool
+                    .transactions()
+                    .iter()
+                    .map(transaction_json)
+                    .collect())
+            }
+            "wallet_address" => Ok(json!(hex::encode(wallet.key_pair.public))),
+            "send_transaction" => {
+                let (payee, amount): (String, u64) = parse_params(params)?;
+                let payee = parse_address(&payee)?;
+                // the mempool checks the balance, counting what the wallet already has pending
+                match wallet.send_transaction(payee, amount) {
+                    Ok(hash) => Ok(json!(hex_hash(hash))),
+                    Err(SendError::Rejected(MempoolError::InsufficientBalance)) => {
+                        Err(RpcError::new(REJECTED, "Insufficient balance"))
+                    }
+                    Err(e) => Err(RpcError::new(REJECTED, &e.to_string())),
+                }
+            }
+            "recent_events" => Ok(self.events.recent().iter().map(Event::to_json).collect()),
+            "mining_status" => Ok(json!(node.is_mining())),
+            "set_mining" => {
+                let (enabled,): (bool,) = parse_params(params)?;
+                node.set_mining(enabled);
+                Ok(json!(enabled))
+            }
+            "peers" => Ok(node
+                .connected_peers()
+                .into_iter()
+                .map(|(peer, score)| json!({ "peer": peer.to_string(), "score": score }))
+                .collect()),
+            "banned_peers" => {
+                let now = node.peers.lock().unwrap().now();
+                Ok(node
+                    .banned_peers()
+                    .into_iter()
+                    .map(|(peer, ban)| {
+                        json!({
+                            "peer": peer.to_string(),
+                            // null for persistent bans
+                            "expires_in": ban.expires_in(now),
+                            "reason": ban.reason,
+                        })
+                    })
+                    .collect())
+            }
+            "add_peer" => {
+                let (address,): (String,) = parse_params(params)?;
+                let address = address
+                    .parse()
+                    .map_err(|_| RpcError::new(INVALID_PARAMS, "Invalid peer address"))?;
+                node.add_peer(address);
+                Ok(json!(true))
+            }
+            "ban_peer" => {
+                let (peer, seconds): (String, Option<u64>) = parse_params(params)?;
+                node.ban_peer(parse_peer(&peer)?, seconds.map(Duration::from_secs));
+                Ok(json!(true))
+            }
+            "unban_peer" => {
+                let (peer,): (String,) = parse_params(params)?;
+                let peer = parse_peer(&peer)?;
+                let banned = node.banned_peers().iter().any(|(p, _)| p == &peer);
+                node.unban_peer(peer);
+                Ok(json!(banned))
+            }
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, "Method not found")),
+        }
+    }
+}
+
+fn error(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": error.code, "message": error.message },
+        "id": id,
+    })
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|_| RpcError::new(INVALID_PARAMS, "Invalid params"))
+}
+
+fn parse_hash(hash: &str) -> Result<Hash, RpcError> {
+    Hash::from_str_radix(hash.trim_start_matches("0x"), 16)
+        .map_err(|_| RpcError::new(INVALID_PARAMS, "Invalid hash"))
+}
+
+fn parse_peer(peer: &str) -> Result<PeerId, RpcError> {
+    peer.parse()
+        .map_err(|_| RpcError::new(INVALID_PARAMS, "Invalid peer id"))
+}
+
+pub(crate) fn parse_address(address: &str) -> Result<PublicKey, RpcError> {
+    hex::decode(address)
+        .ok()
+        .and_then(|bytes| PublicKey::from_bytes(&bytes).ok())
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "Invalid address"))
+}
+
+pub(crate) fn hex_hash(hash: Hash) -> String {
+    format!("{:x}", hash)
+}
+
+pub(crate) fn block_json(block: &Block) -> Value {
+    json!({
+        "hash": hex_hash(block.hash()),
+        "prev_hash": hex_hash(block.header.prev_hash),
+        "transactions_root": hex_hash(block.header.transactions_root),
+        "nonce": block.header.nonce,
+        "timestamp": block.header.timestamp,
+        "mined_by": hex::encode(block.header.mined_by),
+        "transactions": block.transactions.iter().map(transaction_json).collect::<Vec<_>>(),
+    })
+}
+
+pub(crate) fn transaction_json(transaction: &Transaction) -> Value {
+    json!({
+        "hash": hex_hash(transaction.hash()),
+        "from": hex::encode(transaction.data.from),
+        "to": hex::encode(transaction.data.to),
+        "amount": transaction.data.amount,
+    })
+}
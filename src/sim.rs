@@ -0,0 +1,317 @@
+use super::*;
+use ed25519_dalek::SecretKey;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+// Virtual time advances in steps of this many milliseconds
+const TICK_MS: u64 = 100;
+
+#[derive(Debug, Clone)]
+pub struct SimNodeConfig {
+    // hashes per second
+    pub hashrate: f64,
+}
+
+/// Cuts `isolated` nodes off from all the others between `start` and `end` seconds.
+#[derive(Debug, Clone)]
+pub struct Partition {
+    pub start: u64,
+    pub end: u64,
+    pub isolated: Vec<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub nodes: Vec<SimNodeConfig>,
+    // simulated seconds
+    pub duration: u64,
+    // each message is delayed by a random latency in this range, in milliseconds
+    pub latency: (u64, u64),
+    // chance of any single message getting lost
+    pub loss: f64,
+    pub partitions: Vec<Partition>,
+    pub seed: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            nodes: vec![SimNodeConfig { hashrate: 100. }; 4],
+            duration: 3600,
+            latency: (50, 500),
+            loss: 0.,
+            partitions: vec![],
+            seed: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimReport {
+    pub blocks_mined: usize,
+    // mined blocks that didn't make it into the heaviest chain
+    pub orphaned: usize,
+    pub orphan_rate: f64,
+    pub reorgs: usize,
+    pub max_reorg_depth: usize,
+    // whether all nodes ended up on the same tip
+    pub converged: bool,
+    // share of time all nodes were on the same tip
+    pub convergence: f64,
+    pub height: usize,
+    pub weight: u32,
+    pub mean_block_interval: f64,
+}
+
+impl fmt::Display for SimReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Heaviest chain: height {}, weight {}, {:.1}s between blocks",
+            self.height, self.weight, self.mean_block_interval
+        )?;
+        writeln!(
+            f,
+            "Blocks mined: {}, orphaned: {} ({:.1}%)",
+            self.blocks_mined,
+            self.orphaned,
+            self.orphan_rate * 100.
+        )?;
+        writeln!(
+            f,
+            "Reorgs: {}, deepest: {} blocks",
+            self.reorgs, self.max_reorg_depth
+        )?;
+        write!(
+            f,
+            "Converged: {}, on the same tip {:.1}% of the time",
+            self.converged,
+            self.convergence * 100.
+        )
+    }
+}
+
+// SplitMix64, small and the same on every platform
+struct SimRng(u64);
+
+impl SimRng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn range(&mut self, (min, max): (u64, u64)) -> u64 {
+        min + self.next_u64() % (max - min + 1)
+    }
+}
+
+struct SimNode {
+    key: PublicKey,
+    hashrate: f64,
+    blockchain: Blockchain,
+}
+
+/// Deterministic network simulation of the chain rules.
+///
+/// It models nodes at the chain level only: each is a bare `Blockchain` that mines in virtual
+/// time and sends its whole chain to the others over a simulated network. Blocks go through
+/// `Blockchain::add_block` with proof of work from `mining::mined`, and received chains are
+/// checked by `Blockchain::validate_chain` and chosen by `Blockchain::prefers`. Compact
+/// blocks, headers-first sync, gossip validation and peer scoring aren't modeled. The
+/// orphan rates and reorgs it reports show how difficulty adjustment and fork choice behave,
+/// not how the real node does. A run only depends on its `SimConfig` and seed.
+pub struct Simulator {
+    config: SimConfig,
+    rng: SimRng,
+    // milliseconds since genesis
+    time: u64,
+    // what the nodes see as the current time, follows `time`
+    clock: Arc<MockClock>,
+    nodes: Vec<SimNode>,
+    // (delivery time, sequence number) -> (sender, receiver, chain)
+    in_flight: BTreeMap<(u64, u64), (usize, usize, Arc<Blockchain>)>,
+    sent: u64,
+    mined: Vec<Hash>,
+    reorgs: usize,
+    max_reorg_depth: usize,
+    converged_ticks: u64,
+}
+
+impl Simulator {
+    pub fn new(config: SimConfig) -> Self {
+        let clock = Arc::new(MockClock::new(GENESIS_TIMESTAMP));
+        let genesis = Blockchain::new(&ChainSpec::new("simulation"), clock.clone());
+        let nodes = config
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                let secret = SecretKey::from_bytes(&[i as u8 + 1; 32]).unwrap();
+                SimNode {
+                    key: PublicKey::from(&secret),
+                    hashrate: node.hashrate,
+                    blockchain: genesis.clone(),
+                }
+            })
+            .collect();
+
+        Self {
+            rng: SimRng(config.seed),
+            config,
+            time: 0,
+            clock,
+            nodes,
+            in_flight: BTreeMap::new(),
+            sent: 0,
+            mined: vec![],
+            reorgs: 0,
+            max_reorg_depth: 0,
+            converged_ticks: 0,
+        }
+    }
+
+    pub fn run(mut self) -> SimReport {
+        let ticks = self.config.duration * 1000 / TICK_MS;
+        for _ in 0..ticks {
+            self.time += TICK_MS;
+            self.clock.set(GENESIS_TIMESTAMP + self.time / 1000);
+            self.deliver();
+            for node in 0..self.nodes.len() {
+                self.mine(node);
+            }
+
+            let tip = self.nodes[0].blockchain.blocks.last().map(|b| b.hash());
+            if self
+                .nodes
+                .iter()
+                .all(|n| n.blockchain.blocks.last().map(|b| b.hash()) == tip)
+            {
+                self.converged_ticks += 1;
+            }
+        }
+        self.report(ticks)
+    }
+
+    fn partitioned(&self, a: usize, b: usize) -> bool {
+        let seconds = self.time / 1000;
+        self.config.partitions.iter().any(|p| {
+            (p.start..p.end).contains(&seconds)
+                && p.isolated.contains(&a) != p.isolated.contains(&b)
+        })
+    }
+
+    fn broadcast(&mut self, from: usize) {
+        let chain = Arc::new(self.nodes[from].blockchain.clone());
+        for to in 0..self.nodes.len() {
+            if to == from || self.rng.next_f64() < self.config.loss {
+                continue;
+            }
+            let delivery = self.time + self.rng.range(self.config.latency);
+            self.sent += 1;
+            self.in_flight
+                .insert((delivery, self.sent), (from, to, chain.clone()));
+        }
+    }
+
+    fn deliver(&mut self) {
+        while let Some(entry) = self.in_flight.first_entry() {
+            if entry.key().0 > self.time {
+                break;
+            }
+            let (from, to, chain) = entry.remove();
+            if self.partitioned(from, to) {
+                continue;
+            }
+
+            // validated and chosen the way `Node` does it for gossiped chains
+            let node = &mut self.nodes[to];
+            let Ok(chain) = node.blockchain.validate_chain(chain.blocks.clone()) else {
+                continue;
+            };
+            if !node.blockchain.prefers(&chain) {
+                continue;
+            }
+            let common = node
+                .blockchain
+                .blocks
+                .iter()
+                .zip(&chain.blocks)
+                .take_while(|(a, b)| a.hash() == b.hash())
+                .count();
+            let depth = node.blockchain.blocks.len() - common;
+            if depth > 0 {
+                self.reorgs += 1;
+                self.max_reorg_depth = self.max_reorg_depth.max(depth);
+            }
+            node.blockchain = chain;
+            self.broadcast(to);
+        }
+    }
+
+    // Decides whether the node finds a block during this tick and if so, actually mines it
+    fn mine(&mut self, index: usize) {
+        let node = &self.nodes[index];
+        let mut block = node.blockchain.generate_block(node.key);
+        let difficulty = node.blockchain.difficulty(&block.header);
+
+        let success = 0.5f64.powi(difficulty as i32 + 1);
+        let hashes = node.hashrate * TICK_MS as f64 / 1000.;
+        if self.rng.next_f64() >= 1. - (1. - success).powf(hashes) {
+            return;
+        }
+
+        block.header.nonce = self.rng.next_u64() / 2;
+        while !mining::mined(&block.header, difficulty) {
+            block.header.nonce += 1;
+        }
+        let hash = block.hash();
+        if self.nodes[index].blockchain.add_block(block).is_ok() {
+            self.mined.push(hash);
+            self.broadcast(index);
+        }
+    }
+
+    fn report(&self, ticks: u64) -> SimReport {
+        let best = self
+            .nodes
+            .iter()
+            .map(|n| &n.blockchain)
+            .max_by_key(|b| b.weight)
+            .unwrap();
+        let in_best: HashSet<Hash> = best.blocks.iter().map(|b| b.hash()).collect();
+        let orphaned = self.mined.iter().filter(|h| !in_best.contains(h)).count();
+
+        let tip = best.blocks.last().map(|b| b.hash());
+        let blocks = best.blocks.len() - 1;
+        let span = best.blocks.last().unwrap().header.timestamp - GENESIS_TIMESTAMP;
+
+        SimReport {
+            blocks_mined: self.mined.len(),
+            orphaned,
+            orphan_rate: orphaned as f64 / self.mined.len().max(1) as f64,
+            reorgs: self.reorgs,
+            max_reorg_depth: self.max_reorg_depth,
+            converged: self
+                .nodes
+                .iter()
+                .all(|n| n.blockchain.blocks.last().map(|b| b.hash()) == tip),
+            convergence: self.converged_ticks as f64 / ticks.max(1) as f64,
+            height: blocks,
+            weight: best.weight,
+            mean_block_interval: span as f64 / blocks.max(1) as f64,
+        }
+    }
+}
+
+/// Runs a simulation to its end.
+pub fn simulate(config: SimConfig) -> SimReport {
+    Simulator::new(config).run()
+}
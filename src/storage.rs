@@ -0,0 +1,102 @@
+use super::*;
+use crate::peers::Ban;
+use std::fs;
+use std::path::Path;
+
+// All live next to the network key in the data directory
+const CHAIN_FILE: &str = "chain";
+const MEMPOOL_FILE: &str = "mempool";
+const BANS_FILE: &str = "bans";
+const WALLET_KEY_FILE: &str = "wallet_key";
+
+/// Writes the active chain, the pending transactions and the banned peers to `data_dir`.
+pub fn save(
+    data_dir: &Path,
+    blockchain: &Blockchain,
+    mempool: &Mempool,
+    bans: &[(PeerId, Ban)],
+) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(data_dir)?;
+    write(
+        &data_dir.join(CHAIN_FILE),
+        &bincode::serialize(&blockchain.blocks)?,
+    )?;
+    write(
+        &data_dir.join(MEMPOOL_FILE),
+        &bincode::serialize(mempool.transactions())?,
+    )?;
+    let bans: Vec<(Vec<u8>, &Ban)> = bans
+        .iter()
+        .map(|(peer, ban)| (peer.to_bytes(), ban))
+        .collect();
+    write(&data_dir.join(BANS_FILE), &bincode::serialize(&bans)?)?;
+    Ok(())
+}
+
+/// Reads back the chain `save` wrote, validating it again on top of `genesis`,
+/// which it has to start with. Nothing saved yet is not an error.
+pub fn load_chain(
+    data_dir: &Path,
+    genesis: &Blockchain,
+) -> Result<Option<Blockchain>, Box<dyn Error>> {
+    let path = data_dir.join(CHAIN_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let blocks: Vec<Block> = bincode::deserialize(&fs::read(path)?)?;
+    if blocks.first().map(|b| b.hash()) != genesis.genesis_hash() {
+        return Err("saved chain belongs to another network".into());
+    }
+    let blockchain = genesis
+        .rebuild(blocks)
+        .map_err(|e| format!("saved chain is invalid: {:?}", e))?;
+    Ok(Some(blockchain))
+}
+
+/// The transactions that were pending, they still have to go through the mempool again.
+pub fn load_mempool(data_dir: &Path) -> Result<Vec<Transaction>, Box<dyn Error>> {
+    let path = data_dir.join(MEMPOOL_FILE);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    Ok(bincode::deserialize(&fs::read(path)?)?)
+}
+
+/// The peers that were banned, temporary bans that ran out meanwhile included.
+pub fn load_bans(data_dir: &Path) -> Result<Vec<(PeerId, Ban)>, Box<dyn Error>> {
+    let path = data_dir.join(BANS_FILE);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let bans: Vec<(Vec<u8>, Ban)> = bincode::deserialize(&fs::read(path)?)?;
+    bans.into_iter()
+        .map(|(peer, ban)| Ok((PeerId::from_bytes(&peer)?, ban)))
+        .collect()
+}
+
+/// The wallet's key pair, generated on first use, so the coins it mined are still there
+/// after a restart.
+pub fn load_wallet_key(data_dir: &Path) -> Result<Keypair, Box<dyn Error>> {
+    let path = data_dir.join(WALLET_KEY_FILE);
+    if path.exists() {
+        return Ok(Keypair::from_bytes(&fs::read(path)?)?);
+    }
+
+    let key_pair = Keypair::generate(&mut rand::rngs::OsRng {});
+    fs::create_dir_all(data_dir)?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    // the key is all it takes to spend the wallet's coins
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, &key_pair.to_bytes())?;
+    Ok(key_pair)
+}
+
+// Through a temporary file, so that a crash while writing leaves the previous version
+fn write(path: &Path, contents: &[u8]) -> Result<(), Box<dyn Error>> {
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, contents)?;
+    fs::rename(temporary, path)?;
+    Ok(())
+}
@@ -0,0 +1,152 @@
+use super::*;
+use crate::events::{Event, EventBus};
+use crate::rpc::parse_address;
+use async_std::net::{TcpListener, TcpStream};
+use futures::channel::oneshot;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tungstenite::Message;
+
+// Localhost only, like the JSON-RPC server
+pub const DEFAULT_SUBSCRIPTION_ADDRESS: &str = "127.0.0.1:9546";
+
+/// What a connection wants to hear about, set by sending
+/// `{"subscribe": ["block_connected", "tx_received", ...], "addresses": [...]}`.
+/// Both are optional, leaving either out means everything.
+#[derive(Deserialize)]
+struct Subscription {
+    subscribe: Option<Vec<String>>,
+    #[serde(default)]
+    addresses: Vec<String>,
+}
+
+struct Filter {
+    events: Option<Vec<String>>,
+    addresses: Vec<PublicKey>,
+}
+
+impl Filter {
+    fn parse(text: &str) -> Result<Self, String> {
+        let subscription: Subscription = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        let addresses = subscription
+            .addresses
+            .iter()
+            .map(|a| parse_address(a).map_err(|e| e.message))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            events: subscription.subscribe,
+            addresses,
+        })
+    }
+
+    fn matches(&self, event: &Event, json: &Value) -> bool {
+        self.events
+            .as_ref()
+            .is_none_or(|names| names.iter().any(|n| json["event"] == n.as_str()))
+            && (self.addresses.is_empty() || self.addresses.iter().any(|a| event.involves(a)))
+    }
+}
+
+/// Streams node events as JSON over WebSocket. Nothing is sent before the first subscription,
+/// which is acknowledged with `{"event": "subscribed"}`, and it can be changed any time.
+/// Connections are served as tasks on the async runtime, waiting on both the events and the socket.
+pub struct SubscriptionServer {
+    address: SocketAddr,
+    // stops accepting connections
+    stop: Option<oneshot::Sender<()>>,
+    task: Option<task::JoinHandle<()>>,
+}
+
+impl SubscriptionServer {
+    pub fn start(address: SocketAddr, events: EventBus) -> Result<Self, Box<dyn Error>> {
+        let listener = std::net::TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let listener = TcpListener::from(listener);
+        let (stop, mut stopped) = oneshot::channel::<()>();
+
+        let task = task::spawn(async move {
+            let mut incoming = listener.incoming().fuse();
+            loop {
+                select! {
+                    stream = incoming.select_next_some() => {
+                        let Ok(stream) = stream else {
+                            continue;
+                        };
+                        task::spawn(serve(stream, events.subscribe()));
+                    },
+                    _ = stopped => break,
+                }
+            }
+        });
+
+        Ok(Self {
+            address,
+            stop: Some(stop),
+            task: Some(task),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for SubscriptionServer {
+    fn drop(&mut self) {
+        // sent rather than dropped, select! takes a cancelled receiver for a finished one
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(task) = self.task.take() {
+            task::block_on(task);
+        }
+    }
+}
+
+// Forwards events and reads subscriptions, whichever comes first, until either side is gone
+async fn serve(stream: TcpStream, mut receiver: broadcast::Receiver<Event>) {
+    let Ok(socket) = async_tungstenite::accept_async(stream).await else {
+        return;
+    };
+    let (mut sink, messages) = socket.split();
+    let mut messages = messages.fuse();
+    let mut filter: Option<Filter> = None;
+
+    loop {
+        let event = receiver.recv().fuse();
+        futures::pin_mut!(event);
+        let reply = select! {
+            event = event => match event {
+                Ok(event) => {
+                    if let Some(filter) = &filter {
+                        let json = event.to_json();
+                        if filter.matches(&event, &json)
+                            && sink.send(Message::Text(json.to_string())).await.is_err()
+                        {
+                            return;
+                        }
+                    }
+                    continue;
+                }
+                // too slow to keep up, the missed events are gone
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            },
+            message = messages.next() => match message {
+                Some(Ok(Message::Text(text))) => match Filter::parse(&text) {
+                    Ok(new_filter) => {
+                        filter = Some(new_filter);
+                        json!({ "event": "subscribed" })
+                    }
+                    Err(message) => json!({ "event": "error", "message": message }),
+                },
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => continue,
+            },
+        };
+        if sink.send(Message::Text(reply.to_string())).await.is_err() {
+            return;
+        }
+    }
+}
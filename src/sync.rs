@@ -0,0 +1,715 @@
+use super::*;
+use crate::blockchain::{BlockValidationError, HeaderChain};
+use crate::message::NetworkMessage;
+use crate::peers::Misbehaviour;
+use async_trait::async_trait;
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed};
+use libp2p::request_response::{
+    ProtocolName, ProtocolSupport, RequestId, RequestResponse, RequestResponseCodec,
+    RequestResponseConfig,
+};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use tracing::{debug, info, warn};
+
+// Upper bound for a single request or response, a full header chain has to fit in it
+const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+// How many block bodies are asked from one peer in a single request
+const BLOCKS_PER_REQUEST: usize = 16;
+// How many body requests can be in flight to one peer at a time
+const REQUESTS_PER_PEER: usize = 2;
+
+// Namespaced per chain, so peers of other networks can't sync with us
+#[derive(Debug, Clone)]
+pub struct SyncProtocol(String);
+
+impl ProtocolName for SyncProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+/// Summary of a node's active chain, exchanged when peers connect.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Status {
+    pub tip: Hash,
+    // of the tip, the genesis block is at 0
+    pub height: usize,
+    pub weight: u32,
+    // the sender's own clock, peers adjust theirs towards the network's
+    pub timestamp: u64,
+}
+
+impl Status {
+    pub fn new(blockchain: &Blockchain) -> Self {
+        Self {
+            tip: blockchain
+                .blocks
+                .last()
+                .map(|b| b.hash())
+                .unwrap_or_default(),
+            height: blockchain.blocks.len().saturating_sub(1),
+            weight: blockchain.weight,
+            timestamp: blockchain.clock().local_now(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum SyncRequest {
+    Status(Status),
+    Headers,
+    Blocks(Vec<Hash>),
+    // transactions of a block at the given indexes, to complete a compact block
+    Transactions(Hash, Vec<usize>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum SyncResponse {
+    Status(Status),
+    Headers(Vec<BlockHeader>),
+    Blocks(Vec<Block>),
+    Transactions(Vec<Transaction>),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SyncCodec;
+
+#[async_trait]
+impl RequestResponseCodec for SyncCodec {
+    type Protocol = SyncProtocol;
+    type Request = SyncRequest;
+    type Response = SyncResponse;
+
+    async fn read_request<T>(&mut self, _: &SyncProtocol, io: &mut T) -> io::Result<SyncRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, MAX_MESSAGE_SIZE).await?;
+        match NetworkMessage::decode(&data) {
+            Ok((_, NetworkMessage::SyncRequest(request))) => Ok(request),
+            Ok(_) => Err(unexpected_message()),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+
+    async fn read_response<T>(&mut self, _: &SyncProtocol, io: &mut T) -> io::Result<SyncResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, MAX_MESSAGE_SIZE).await?;
+        match NetworkMessage::decode(&data) {
+            Ok((_, NetworkMessage::SyncResponse(response))) => Ok(response),
+            Ok(_) => Err(unexpected_message()),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &SyncProtocol,
+        io: &mut T,
+        request: SyncRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, NetworkMessage::SyncRequest(request).encode()).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &SyncProtocol,
+        io: &mut T,
+        response: SyncResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, NetworkMessage::SyncResponse(response).encode()).await?;
+        io.close().await
+    }
+}
+
+fn unexpected_message() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "unexpected message on the sync protocol",
+    )
+}
+
+pub fn behaviour(chain_id: &str) -> RequestResponse<SyncCodec> {
+    let protocol = SyncProtocol(format!("/blockchain/{}/sync/1", chain_id));
+    RequestResponse::new(
+        SyncCodec,
+        std::iter::once((protocol, ProtocolSupport::Full)),
+        RequestResponseConfig::default(),
+    )
+}
+
+/// Answers a sync request from our active blockchain.
+pub fn respond(blockchain: &Blockchain, request: SyncRequest) -> SyncResponse {
+    match request {
+        SyncRequest::Status(_) => SyncResponse::Status(Status::new(blockchain)),
+        SyncRequest::Headers => SyncResponse::Headers(blockchain.headers()),
+        SyncRequest::Blocks(hashes) => {
+            let index: HashMap<Hash, &Block> =
+                blockchain.blocks.iter().map(|b| (b.hash(), b)).collect();
+            SyncResponse::Blocks(
+                hashes
+                    .iter()
+                    .filter_map(|hash| index.get(hash).map(|b| (*b).clone()))
+                    .collect(),
+            )
+        }
+        SyncRequest::Transactions(hash, indexes) => SyncResponse::Transactions(
+            match blockchain.blocks.iter().rev().find(|b| b.hash() == hash) {
+                Some(block) => indexes
+                    .iter()
+                    .filter_map(|i| block.transactions.get(*i).cloned())
+                    .collect(),
+                // not ours anymore, the requester asks for the whole block then
+                None => vec![],
+            },
+        ),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncStage {
+    #[default]
+    Idle,
+    Headers,
+    Bodies,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SyncProgress {
+    pub stage: SyncStage,
+    pub peers: usize,
+    pub target_height: usize,
+    pub target_weight: u32,
+    pub downloaded: usize,
+    pub total: usize,
+}
+
+impl fmt::Display for SyncProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.stage {
+            SyncStage::Idle => write!(f, "Synchronized"),
+            SyncStage::Headers => write!(f, "Downloading headers from {} peers", self.peers),
+            SyncStage::Bodies => write!(
+                f,
+                "Downloading blocks {}/{} of chain with height {} and weight {} from {} peers",
+                self.downloaded, self.total, self.target_height, self.target_weight, self.peers
+            ),
+        }
+    }
+}
+
+/// Headers-first initial block download.
+///
+/// Header chains are requested from every connected peer and checked for proof of work,
+/// the heaviest one heavier than ours is chosen and its missing bodies are then fetched
+/// in chunks from all peers that reported the same chain.
+#[derive(Default)]
+pub struct Synchronizer {
+    pub progress: Arc<Mutex<SyncProgress>>,
+    header_requests: HashMap<RequestId, PeerId>,
+    candidates: Vec<(PeerId, Vec<Hash>, HeaderChain)>,
+    target: Vec<Hash>,
+    fork_height: usize,
+    sources: Vec<(PeerId, Vec<Hash>)>,
+    queue: VecDeque<(usize, Vec<Hash>)>,
+    body_requests: HashMap<RequestId, (PeerId, usize, Vec<Hash>)>,
+    bodies: HashMap<Hash, Block>,
+    // peers that sent us invalid data, to be reported by the caller
+    pub misbehaviours: Vec<(PeerId, Misbehaviour)>,
+}
+
+impl Synchronizer {
+    pub fn stage(&self) -> SyncStage {
+        self.progress.lock().unwrap().stage
+    }
+
+    /// Starts a new synchronization round unless one is already running.
+    pub fn start<'a>(
+        &mut self,
+        sync: &mut RequestResponse<SyncCodec>,
+        peers: impl Iterator<Item = &'a PeerId>,
+    ) {
+        if self.stage() != SyncStage::Idle {
+            return;
+        }
+
+        for peer in peers {
+            let request_id = sync.send_request(peer, SyncRequest::Headers);
+            self.header_requests.insert(request_id, *peer);
+        }
+
+        if !self.header_requests.is_empty() {
+            info!(peers = self.header_requests.len(), "requesting headers");
+            *self.progress.lock().unwrap() = SyncProgress {
+                stage: SyncStage::Headers,
+                peers: self.header_requests.len(),
+                ..Default::default()
+            };
+        }
+    }
+
+    /// Starts syncing right away when a peer reports a heavier chain than ours.
+    pub fn on_status<'a>(
+        &mut self,
+        sync: &mut RequestResponse<SyncCodec>,
+        status: &Status,
+        active_blockchain: &Blockchain,
+        peers: impl Iterator<Item = &'a PeerId>,
+    ) {
+        if status.weight > active_blockchain.weight {
+            self.start(sync, peers);
+        }
+    }
+
+    /// Handles a response to one of our requests, returning the blocks of the heavier
+    /// chain once all of them are downloaded. They are still to be validated.
+    pub fn on_response(
+        &mut self,
+        sync: &mut RequestResponse<SyncCodec>,
+        request_id: RequestId,
+        response: SyncResponse,
+        active_blockchain: &Blockchain,
+    ) -> Option<Vec<Block>> {
+        match response {
+            SyncResponse::Status(_) | SyncResponse::Transactions(_) => None,
+            SyncResponse::Headers(headers) => {
+                if let Some(peer) = self.header_requests.remove(&request_id) {
+                    let hashes: Vec<Hash> = headers.iter().map(|h| h.hash()).collect();
+                    if hashes.first() != active_blockchain.genesis_hash().as_ref() {
+                        self.misbehaviours.push((
+                            peer,
+                            Misbehaviour::InvalidHeaders(BlockValidationError::GenesisMismatch),
+                        ));
+                    } else {
+                        match HeaderChain::construct(headers, active_blockchain.latest_timestamp())
+                        {
+                            Ok(header_chain) => self.candidates.push((peer, hashes, header_chain)),
+                            Err(e) => self
+                                .misbehaviours
+                                .push((peer, Misbehaviour::InvalidHeaders(e))),
+                        }
+                    }
+                    self.headers_received(sync, active_blockchain);
+                }
+                None
+            }
+            SyncResponse::Blocks(blocks) => {
+                let (peer, height, hashes) = self.body_requests.remove(&request_id)?;
+                // a body that doesn't match its header counts as missing
+                let received = blocks.len();
+                let mut blocks: HashMap<Hash, Block> = blocks
+                    .into_iter()
+                    .filter(|b| {
+                        b.header.transactions_root == Block::transactions_root(&b.transactions)
+                    })
+                    .map(|b| (b.hash(), b))
+                    .collect();
+                if blocks.len() < received {
+                    self.misbehaviours
+                        .push((peer, Misbehaviour::WrongBlockBodies));
+                }
+                let missing: Vec<Hash> = hashes
+                    .into_iter()
+                    .filter(|hash| match blocks.remove(hash) {
+                        Some(block) => {
+                            self.bodies.insert(*hash, block);
+                            false
+                        }
+                        None => true,
+                    })
+                    .collect();
+
+                if !missing.is_empty() {
+                    // the peer doesn't have what it advertised, don't ask it again
+                    self.sources.retain(|(p, _)| p != &peer);
+                    self.queue.push_back((height, missing));
+                }
+                self.progress.lock().unwrap().downloaded = self.bodies.len();
+
+                self.request_bodies(sync);
+                self.bodies_received(sync, active_blockchain)
+            }
+        }
+    }
+
+    /// Handles a request that couldn't be completed by the peer.
+    pub fn on_failure(
+        &mut self,
+        sync: &mut RequestResponse<SyncCodec>,
+        request_id: RequestId,
+        active_blockchain: &Blockchain,
+    ) -> Option<Vec<Block>> {
+        if self.header_requests.remove(&request_id).is_some() {
+            self.headers_received(sync, active_blockchain);
+        } else if let Some((peer, height, hashes)) = self.body_requests.remove(&request_id) {
+            self.sources.retain(|(p, _)| p != &peer);
+            self.queue.push_back((height, hashes));
+            self.request_bodies(sync);
+            return self.bodies_received(sync, active_blockchain);
+        }
+        None
+    }
+
+    fn headers_received(
+        &mut self,
+        sync: &mut RequestResponse<SyncCodec>,
+        active_blockchain: &Blockchain,
+    ) {
+        if !self.header_requests.is_empty() {
+            return;
+        }
+
+        let candidates = std::mem::take(&mut self.candidates);
+        let best = candidates
+            .iter()
+            .filter(|(_, _, chain)| chain.weight > active_blockchain.weight)
+            .max_by_key(|(_, _, chain)| chain.weight);
+
+        let (target, target_weight) = match best {
+            Some((_, hashes, chain)) => (hashes.clone(), chain.weight),
+            None => {
+                debug!("no peer has a heavier chain");
+                self.reset();
+                return;
+            }
+        };
+
+        // blocks we already have don't need to be downloaded again
+        self.fork_height = active_blockchain
+            .blocks
+            .iter()
+            .zip(target.iter())
+            .take_while(|(block, hash)| &block.hash() == *hash)
+            .count();
+
+        self.sources = candidates
+            .into_iter()
+            .map(|(peer, hashes, _)| (peer, hashes))
+            .collect();
+        self.queue = target[self.fork_height..]
+            .chunks(BLOCKS_PER_REQUEST)
+            .enumerate()
+            .map(|(i, chunk)| (self.fork_height + i * BLOCKS_PER_REQUEST, chunk.to_vec()))
+            .collect();
+
+        info!(
+            height = target.len() - 1,
+            weight = target_weight,
+            fork_height = self.fork_height,
+            "downloading blocks"
+        );
+        *self.progress.lock().unwrap() = SyncProgress {
+            stage: SyncStage::Bodies,
+            peers: self.sources.len(),
+            target_height: target.len() - 1,
+            target_weight,
+            downloaded: 0,
+            total: target.len() - self.fork_height,
+        };
+        self.target = target;
+
+        self.request_bodies(sync);
+    }
+
+    fn request_bodies(&mut self, sync: &mut RequestResponse<SyncCodec>) {
+        let mut postponed = VecDeque::new();
+
+        while let Some((height, hashes)) = self.queue.pop_front() {
+            let last = height + hashes.len() - 1;
+            // any peer whose chain has the same hash at the last height has the whole chunk
+            let source = self
+                .sources
+                .iter()
+                .filter(|(_, chain)| chain.get(last) == self.target.get(last))
+                .map(|(peer, _)| peer)
+                .filter(|peer| {
+                    self.body_requests
+                        .values()
+                        .filter(|(p, _, _)| p == *peer)
+                        .count()
+                        < REQUESTS_PER_PEER
+                })
+                .min_by_key(|peer| {
+                    self.body_requests
+                        .values()
+                        .filter(|(p, _, _)| p == *peer)
+                        .count()
+                })
+                .copied();
+
+            match source {
+                Some(peer) => {
+                    let request_id = sync.send_request(&peer, SyncRequest::Blocks(hashes.clone()));
+                    self.body_requests
+                        .insert(request_id, (peer, height, hashes));
+                }
+                None => postponed.push_back((height, hashes)),
+            }
+        }
+
+        self.queue = postponed;
+    }
+
+    fn bodies_received(
+        &mut self,
+        sync: &mut RequestResponse<SyncCodec>,
+        active_blockchain: &Blockchain,
+    ) -> Option<Vec<Block>> {
+        if !self.body_requests.is_empty() {
+            return None;
+        }
+
+        // nobody left to ask for the remaining blocks
+        if !self.queue.is_empty() {
+            warn!(
+                missing = self.queue.iter().map(|(_, h)| h.len()).sum::<usize>(),
+                "no peer left to download the remaining blocks from"
+            );
+            self.reset();
+            return None;
+        }
+
+        // our chain may have changed since the headers came in, the download only fits on
+        // top of the block it forked from
+        let still_forks = self.fork_height.checked_sub(1).is_none_or(|height| {
+            active_blockchain
+                .blocks
+                .get(height)
+                .map(|b| b.hash())
+                .as_ref()
+                == self.target.get(height)
+        });
+        if !still_forks {
+            info!("active chain changed during the download, starting over");
+            let peers: Vec<PeerId> = self.sources.iter().map(|(peer, _)| *peer).collect();
+            self.reset();
+            self.start(sync, peers.iter());
+            return None;
+        }
+
+        let mut blocks = active_blockchain.blocks[..self.fork_height].to_vec();
+        blocks.extend(
+            self.target[self.fork_height..]
+                .iter()
+                .filter_map(|hash| self.bodies.remove(hash)),
+        );
+        self.reset();
+        Some(blocks)
+    }
+
+    fn reset(&mut self) {
+        let progress = self.progress.clone();
+        let misbehaviours = std::mem::take(&mut self.misbehaviours);
+        *self = Self::default();
+        *progress.lock().unwrap() = SyncProgress::default();
+        self.progress = progress;
+        self.misbehaviours = misbehaviours;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `blocks` blocks mined on top of `blockchain`, alternately fast and slow so they add
+    // weight while the difficulty stays low
+    fn extend(blockchain: &Blockchain, clock: &MockClock, blocks: usize) -> Blockchain {
+        let mut blockchain = blockchain.rebuild(blockchain.blocks.clone()).unwrap();
+        for i in 0..blocks {
+            clock.advance(if i % 2 == 0 { 1 } else { TIME_BASE });
+            let mut block = blockchain.generate_block(PublicKey::default());
+            let difficulty = blockchain.difficulty(&block.header);
+            while !mining::mined(&block.header, difficulty) {
+                block.header.nonce += 1;
+            }
+            blockchain.add_block(block).unwrap();
+        }
+        blockchain
+    }
+
+    fn genesis(clock: &Arc<MockClock>) -> Blockchain {
+        Blockchain::new(&ChainSpec::new("test"), clock.clone())
+    }
+
+    fn bodies(blockchain: &Blockchain, hashes: &[Hash]) -> SyncResponse {
+        SyncResponse::Blocks(
+            hashes
+                .iter()
+                .filter_map(|hash| blockchain.blocks.iter().find(|b| &b.hash() == hash))
+                .cloned()
+                .collect(),
+        )
+    }
+
+    fn header_requests(synchronizer: &Synchronizer) -> HashMap<PeerId, RequestId> {
+        synchronizer
+            .header_requests
+            .iter()
+            .map(|(request, peer)| (*peer, *request))
+            .collect()
+    }
+
+    fn body_requests(synchronizer: &Synchronizer) -> Vec<(RequestId, PeerId, Vec<Hash>)> {
+        synchronizer
+            .body_requests
+            .iter()
+            .map(|(request, (peer, _, hashes))| (*request, *peer, hashes.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn a_heavier_chain_is_downloaded_past_the_fork() {
+        let clock = Arc::new(MockClock::new(GENESIS_TIMESTAMP));
+        let ours = extend(&genesis(&clock), &clock, 2);
+        let theirs = extend(&ours, &clock, 2 * BLOCKS_PER_REQUEST);
+        let mut sync = behaviour("test");
+        let mut synchronizer = Synchronizer::default();
+
+        let peer = PeerId::random();
+        let status = Status::new(&theirs);
+        assert_eq!(status.height, 2 + 2 * BLOCKS_PER_REQUEST);
+        synchronizer.on_status(&mut sync, &status, &ours, [peer].iter());
+        assert_eq!(synchronizer.stage(), SyncStage::Headers);
+
+        let request = header_requests(&synchronizer)[&peer];
+        let headers = SyncResponse::Headers(theirs.headers());
+        assert!(synchronizer
+            .on_response(&mut sync, request, headers, &ours)
+            .is_none());
+        assert_eq!(synchronizer.stage(), SyncStage::Bodies);
+        assert_eq!(synchronizer.fork_height, ours.blocks.len());
+        let progress = synchronizer.progress.lock().unwrap().clone();
+        assert_eq!(progress.target_height, theirs.blocks.len() - 1);
+        assert_eq!(progress.total, 2 * BLOCKS_PER_REQUEST);
+
+        // in chunks, as many at once as the peer is asked for
+        let requests = body_requests(&synchronizer);
+        assert_eq!(requests.len(), REQUESTS_PER_PEER);
+        let mut blocks = None;
+        for (request, _, hashes) in requests {
+            assert_eq!(hashes.len(), BLOCKS_PER_REQUEST);
+            blocks = synchronizer.on_response(&mut sync, request, bodies(&theirs, &hashes), &ours);
+        }
+
+        let hashes: Vec<Hash> = blocks.unwrap().iter().map(|b| b.hash()).collect();
+        let expected: Vec<Hash> = theirs.blocks.iter().map(|b| b.hash()).collect();
+        assert_eq!(hashes, expected);
+        assert_eq!(synchronizer.stage(), SyncStage::Idle);
+        assert!(synchronizer.misbehaviours.is_empty());
+    }
+
+    #[test]
+    fn downloads_start_over_when_our_chain_changes_meanwhile() {
+        let clock = Arc::new(MockClock::new(GENESIS_TIMESTAMP));
+        let ours = extend(&genesis(&clock), &clock, 3);
+        let theirs = extend(&ours, &clock, 2);
+        // shorter than where the download forks from
+        let replaced = extend(&genesis(&clock), &clock, 1);
+        let mut sync = behaviour("test");
+        let mut synchronizer = Synchronizer::default();
+
+        let peer = PeerId::random();
+        synchronizer.start(&mut sync, [peer].iter());
+        let request = header_requests(&synchronizer)[&peer];
+        let headers = SyncResponse::Headers(theirs.headers());
+        synchronizer.on_response(&mut sync, request, headers, &ours);
+        assert_eq!(synchronizer.fork_height, ours.blocks.len());
+
+        let (request, _, hashes) = body_requests(&synchronizer).remove(0);
+        let blocks = bodies(&theirs, &hashes);
+        assert!(synchronizer
+            .on_response(&mut sync, request, blocks, &replaced)
+            .is_none());
+        assert_eq!(synchronizer.stage(), SyncStage::Headers);
+        assert!(header_requests(&synchronizer).contains_key(&peer));
+        assert!(synchronizer.misbehaviours.is_empty());
+    }
+
+    #[test]
+    fn lighter_chains_are_not_downloaded() {
+        let clock = Arc::new(MockClock::new(GENESIS_TIMESTAMP));
+        let theirs = extend(&genesis(&clock), &clock, 1);
+        let ours = extend(&theirs, &clock, 1);
+        let mut sync = behaviour("test");
+        let mut synchronizer = Synchronizer::default();
+
+        let peer = PeerId::random();
+        synchronizer.on_status(&mut sync, &Status::new(&theirs), &ours, [peer].iter());
+        assert_eq!(synchronizer.stage(), SyncStage::Idle);
+
+        // asked anyway, the answer changes nothing
+        synchronizer.start(&mut sync, [peer].iter());
+        let request = header_requests(&synchronizer)[&peer];
+        let headers = SyncResponse::Headers(theirs.headers());
+        assert!(synchronizer
+            .on_response(&mut sync, request, headers, &ours)
+            .is_none());
+        assert_eq!(synchronizer.stage(), SyncStage::Idle);
+        assert!(synchronizer.body_requests.is_empty());
+    }
+
+    #[test]
+    fn peers_sending_wrong_headers_or_bodies_are_reported() {
+        let clock = Arc::new(MockClock::new(GENESIS_TIMESTAMP));
+        let ours = genesis(&clock);
+        let theirs = extend(&ours, &clock, 3);
+        let other_network = extend(
+            &Blockchain::new(&ChainSpec::new("other"), clock.clone()),
+            &clock,
+            3,
+        );
+        let mut sync = behaviour("test");
+        let mut synchronizer = Synchronizer::default();
+
+        let (honest, foreign, failing) = (PeerId::random(), PeerId::random(), PeerId::random());
+        synchronizer.start(&mut sync, [honest, foreign, failing].iter());
+        let requests = header_requests(&synchronizer);
+        let headers = SyncResponse::Headers(other_network.headers());
+        synchronizer.on_response(&mut sync, requests[&foreign], headers, &ours);
+        synchronizer.on_failure(&mut sync, requests[&failing], &ours);
+        let headers = SyncResponse::Headers(theirs.headers());
+        synchronizer.on_response(&mut sync, requests[&honest], headers, &ours);
+        assert_eq!(synchronizer.stage(), SyncStage::Bodies);
+
+        // a body that doesn't match its header
+        let (request, peer, hashes) = body_requests(&synchronizer).remove(0);
+        assert_eq!(peer, honest);
+        let SyncResponse::Blocks(mut blocks) = bodies(&theirs, &hashes) else {
+            unreachable!()
+        };
+        blocks[0].transactions.push(Transaction::new(
+            PublicKey::default(),
+            1,
+            &Keypair::generate(&mut rand::rngs::OsRng {}),
+        ));
+        let blocks =
+            synchronizer.on_response(&mut sync, request, SyncResponse::Blocks(blocks), &ours);
+
+        // and nobody left to get it from
+        assert!(blocks.is_none());
+        assert_eq!(synchronizer.stage(), SyncStage::Idle);
+        let misbehaviours = &synchronizer.misbehaviours;
+        assert_eq!(misbehaviours.len(), 2);
+        assert!(misbehaviours
+            .iter()
+            .any(|(peer, misbehaviour)| peer == &foreign
+                && matches!(
+                    misbehaviour,
+                    Misbehaviour::InvalidHeaders(BlockValidationError::GenesisMismatch)
+                )));
+        assert!(misbehaviours
+            .iter()
+            .any(|(peer, misbehaviour)| peer == &honest
+                && matches!(misbehaviour, Misbehaviour::WrongBlockBodies)));
+    }
+}
@@ -1,17 +1,19 @@
-
 use super::*;
+use crypto_hash::{digest, Algorithm};
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct TransactionData {
     pub from: PublicKey,
     pub to: PublicKey,
     pub amount: u64,
+    // random, so paying someone the same amount twice makes two different transactions
+    pub nonce: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Transaction {
     pub data: TransactionData,
-    pub signature: Signature
+    pub signature: Signature,
 }
 
 impl core::fmt::Debug for Transaction {
@@ -22,21 +24,26 @@ impl core::fmt::Debug for Transaction {
 
 impl Transaction {
     pub fn new(to: PublicKey, amount: u64, key_pair: &Keypair) -> Self {
-        let data = TransactionData { from: key_pair.public, to, amount };
+        let data = TransactionData {
+            from: key_pair.public,
+            to,
+            amount,
+            nonce: rand::random(),
+        };
         let signature = key_pair.sign(&bincode::serialize(&data).unwrap());
 
-        Self {
-            data,
-            signature
-        }
+        Self { data, signature }
+    }
+
+    pub fn hash(&self) -> Hash {
+        let transaction_binary = bincode::serialize(&self).unwrap();
+        digest(Algorithm::SHA256, &transaction_binary)[0..32].into()
     }
 
     pub fn valid(&self) -> bool {
-        self.data.from.verify(
-            &bincode::serialize(&self.data).unwrap(),
-            &self.signature
-        ).is_ok()
+        self.data
+            .from
+            .verify(&bincode::serialize(&self.data).unwrap(), &self.signature)
+            .is_ok()
     }
 }
-
-
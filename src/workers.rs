@@ -0,0 +1,89 @@
+use super::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Condvar;
+
+/// A fixed number of threads for CPU-bound work, so it stays off the async runtime.
+/// At most `max_pending` jobs wait or run at a time, a flood of work beyond that is turned away.
+pub struct WorkerPool {
+    pool: rayon::ThreadPool,
+    pending: Arc<AtomicUsize>,
+    // notified whenever the last pending job finishes
+    idle: Arc<(Mutex<()>, Condvar)>,
+    max_pending: usize,
+}
+
+impl WorkerPool {
+    pub fn new(name: &str, threads: usize, max_pending: usize) -> Self {
+        let name = name.to_string();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(move |i| format!("{}-{}", name, i))
+            .build()
+            .expect("worker threads can be spawned");
+        Self {
+            pool,
+            pending: Default::default(),
+            idle: Default::default(),
+            max_pending,
+        }
+    }
+
+    /// Queues `job`, returning false without running it when the pool is full.
+    pub fn try_spawn(&self, job: impl FnOnce() + Send + 'static) -> bool {
+        if self.pending.fetch_add(1, Ordering::AcqRel) >= self.max_pending {
+            self.pending.fetch_sub(1, Ordering::AcqRel);
+            return false;
+        }
+        let (pending, idle) = (self.pending.clone(), self.idle.clone());
+        self.pool.spawn(move || {
+            job();
+            finished(&pending, &idle);
+        });
+        true
+    }
+
+    /// Queues `job` even when the pool is full, for work that can't be dropped.
+    pub fn spawn(&self, job: impl FnOnce() + Send + 'static) {
+        self.pending.fetch_add(1, Ordering::AcqRel);
+        let (pending, idle) = (self.pending.clone(), self.idle.clone());
+        self.pool.spawn(move || {
+            job();
+            finished(&pending, &idle);
+        });
+    }
+
+    /// Runs `job` on every thread of the pool at once, for work that never waits in line.
+    pub fn spawn_on_all(&self, job: impl Fn() + Send + Sync + 'static) {
+        let job = Arc::new(job);
+        for _ in 0..self.pool.current_num_threads() {
+            let job = job.clone();
+            self.pool.spawn(move || job());
+        }
+    }
+
+    /// Blocks until every queued job has run, jobs from `spawn_on_all` aside.
+    pub fn drain(&self) {
+        let (lock, idle) = &*self.idle;
+        let mut guard = lock.lock().unwrap();
+        while self.pending.load(Ordering::Acquire) > 0 {
+            guard = idle.wait(guard).unwrap();
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+}
+
+fn finished(pending: &AtomicUsize, idle: &(Mutex<()>, Condvar)) {
+    if pending.fetch_sub(1, Ordering::AcqRel) == 1 {
+        // under the lock, so `drain` can't miss it between checking and waiting
+        let _guard = idle.0.lock().unwrap();
+        idle.1.notify_all();
+    }
+}
+
+/// One worker per core.
+pub fn default_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
@@ -0,0 +1,280 @@
+use blockchain_p2p::blockchain::BlockValidationError;
+use blockchain_p2p::*;
+
+fn blockchain(clock: &Arc<MockClock>) -> Blockchain {
+    Blockchain::new(&ChainSpec::new("test"), clock.clone())
+}
+
+fn key() -> PublicKey {
+    Keypair::generate(&mut rand::rngs::OsRng {}).public
+}
+
+fn mine(blockchain: &Blockchain, mut block: Block) -> Block {
+    let difficulty = blockchain.difficulty(&block.header);
+    while !mining::mined(&block.header, difficulty) {
+        block.header.nonce += 1;
+    }
+    block
+}
+
+#[test]
+fn generated_blocks_take_the_clock_time() {
+    let clock = Arc::new(MockClock::new(GENESIS_TIMESTAMP + 60));
+    let blockchain = blockchain(&clock);
+
+    let block = blockchain.generate_block(key());
+    assert_eq!(block.header.timestamp, GENESIS_TIMESTAMP + 60);
+}
+
+#[test]
+fn blocks_too_far_in_the_future_are_rejected() {
+    let clock = Arc::new(MockClock::new(GENESIS_TIMESTAMP + 60));
+    let mut blockchain = blockchain(&clock);
+
+    let mut block = blockchain.generate_block(key());
+    block.header.timestamp = GENESIS_TIMESTAMP + 60 + MAX_FUTURE_DRIFT + 1;
+    let block = mine(&blockchain, block);
+    assert_eq!(
+        blockchain.add_block(block.clone()),
+        Err(BlockValidationError::FutureTimestamp)
+    );
+
+    clock.advance(1);
+    assert_eq!(blockchain.add_block(block), Ok(()));
+}
+
+#[test]
+fn blocks_must_be_later_than_the_median_time_past() {
+    let clock = Arc::new(MockClock::new(GENESIS_TIMESTAMP));
+    let mut blockchain = blockchain(&clock);
+    for _ in 1..MEDIAN_TIME_SPAN {
+        clock.advance(10);
+        let block = mine(&blockchain, blockchain.generate_block(key()));
+        blockchain.add_block(block).unwrap();
+    }
+    let median = GENESIS_TIMESTAMP + 10 * (MEDIAN_TIME_SPAN as u64 / 2);
+
+    let mut block = blockchain.generate_block(key());
+    block.header.timestamp = median;
+    let block = mine(&blockchain, block);
+    assert_eq!(
+        blockchain.add_block(block),
+        Err(BlockValidationError::InvalidTimestamp)
+    );
+
+    // earlier than the previous block is fine, as long as it's past the median
+    let mut block = blockchain.generate_block(key());
+    block.header.timestamp = median + 1;
+    let block = mine(&blockchain, block);
+    assert_eq!(blockchain.add_block(block), Ok(()));
+}
+
+#[test]
+fn generated_blocks_stay_past_the_median_time() {
+    let clock = Arc::new(MockClock::new(GENESIS_TIMESTAMP));
+    let blockchain = blockchain(&clock);
+
+    // a clock behind the chain still produces valid blocks
+    let block = blockchain.generate_block(key());
+    assert_eq!(block.header.timestamp, GENESIS_TIMESTAMP + 1);
+}
+
+#[test]
+fn network_time_follows_the_median_peer() {
+    let local = Arc::new(MockClock::new(GENESIS_TIMESTAMP));
+    let clock = NetworkClock::new(local.clone());
+
+    let offsets = [30, 35, 40, 45, -5000];
+    for offset in offsets.iter().take(4) {
+        clock.add_sample(
+            PeerId::random(),
+            GENESIS_TIMESTAMP.saturating_add_signed(*offset),
+        );
+    }
+    // too few peers to go by
+    assert_eq!(clock.now(), GENESIS_TIMESTAMP);
+
+    clock.add_sample(
+        PeerId::random(),
+        GENESIS_TIMESTAMP.saturating_add_signed(offsets[4]),
+    );
+    assert_eq!(clock.now(), GENESIS_TIMESTAMP + 35);
+
+    local.advance(10);
+    assert_eq!(clock.now(), GENESIS_TIMESTAMP + 45);
+}
+
+#[test]
+fn peers_only_move_the_network_time_while_connected() {
+    let local = Arc::new(MockClock::new(GENESIS_TIMESTAMP));
+    let clock = NetworkClock::new(local.clone());
+
+    // however many have come and gone
+    let peers: Vec<PeerId> = (0..500).map(|_| PeerId::random()).collect();
+    for peer in &peers {
+        clock.add_sample(*peer, GENESIS_TIMESTAMP + 30);
+    }
+    assert_eq!(clock.now(), GENESIS_TIMESTAMP + 30);
+    for peer in &peers {
+        clock.remove_sample(peer);
+    }
+    assert_eq!(clock.now(), GENESIS_TIMESTAMP);
+
+    for _ in 0..5 {
+        clock.add_sample(PeerId::random(), GENESIS_TIMESTAMP + 60);
+    }
+    assert_eq!(clock.now(), GENESIS_TIMESTAMP + 60);
+    // and peers are told the time of this machine
+    assert_eq!(clock.local_now(), GENESIS_TIMESTAMP);
+}
+
+#[test]
+fn difficulty_follows_block_intervals() {
+    let clock = Arc::new(MockClock::new(GENESIS_TIMESTAMP));
+    let mut blockchain = blockchain(&clock);
+
+    // blocks coming in faster than the target interval make mining harder
+    for _ in 0..3 {
+        clock.advance(1);
+        let block = mine(&blockchain, blockchain.generate_block(key()));
+        blockchain.add_block(block).unwrap();
+    }
+    let raised = blockchain.cur_dif;
+    assert!(raised > 0);
+
+    // and slower ones make it easier again
+    clock.advance(TIME_BASE * 4);
+    let block = mine(&blockchain, blockchain.generate_block(key()));
+    blockchain.add_block(block).unwrap();
+    assert!(blockchain.cur_dif < raised);
+}
+
+#[test]
+fn a_rejected_block_leaves_the_balances_alone() {
+    let clock = Arc::new(MockClock::new(GENESIS_TIMESTAMP));
+    let mut blockchain = blockchain(&clock);
+    let miner = Keypair::generate(&mut rand::rngs::OsRng {});
+    clock.advance(TIME_BASE);
+    let block = mine(&blockchain, blockchain.generate_block(miner.public));
+    blockchain.add_block(block).unwrap();
+    let balances = blockchain.balances.clone();
+
+    // a valid payment followed by one whose amount was changed after signing
+    let mut forged = Transaction::new(key(), 1, &miner);
+    forged.data.amount = 2;
+    let mut block = blockchain.generate_block(key());
+    block.add_transaction(Transaction::new(key(), 10, &miner));
+    block.add_transaction(forged);
+    clock.advance(TIME_BASE);
+    let block = mine(&blockchain, block);
+    assert_eq!(
+        blockchain.add_block(block),
+        Err(BlockValidationError::InvalidTransactionSignature)
+    );
+    assert_eq!(blockchain.balances, balances);
+    assert_eq!(blockchain.blocks.len(), 2);
+}
+
+#[test]
+fn transactions_can_only_be_included_once() {
+    let clock = Arc::new(MockClock::new(GENESIS_TIMESTAMP));
+    let mut blockchain = blockchain(&clock);
+    let miner = Keypair::generate(&mut rand::rngs::OsRng {});
+    clock.advance(TIME_BASE);
+    let block = mine(&blockchain, blockchain.generate_block(miner.public));
+    blockchain.add_block(block).unwrap();
+
+    // paying the same again is another transaction
+    let payee = key();
+    let payment = Transaction::new(payee, 10, &miner);
+    assert_ne!(payment.hash(), Transaction::new(payee, 10, &miner).hash());
+
+    let with = |blockchain: &Blockchain, transactions: &[&Transaction]| {
+        clock.advance(TIME_BASE);
+        let mut block = blockchain.generate_block(key());
+        for transaction in transactions {
+            block.add_transaction((*transaction).clone());
+        }
+        mine(blockchain, block)
+    };
+    assert_eq!(
+        blockchain.add_block(with(&blockchain, &[&payment, &payment])),
+        Err(BlockValidationError::DuplicateTransaction)
+    );
+    blockchain
+        .add_block(with(&blockchain, &[&payment]))
+        .unwrap();
+    assert_eq!(
+        blockchain.add_block(with(&blockchain, &[&payment])),
+        Err(BlockValidationError::DuplicateTransaction)
+    );
+
+    // long after, checked on a tip without the block it's in
+    for _ in 0..MEDIAN_TIME_SPAN {
+        blockchain.add_block(with(&blockchain, &[])).unwrap();
+    }
+    let mut tip = blockchain.tip();
+    assert_eq!(
+        tip.add_block(with(&blockchain, &[&payment])),
+        Err(BlockValidationError::DuplicateTransaction)
+    );
+    assert_eq!(blockchain.balances[payee.as_bytes()], 10);
+}
+
+#[test]
+fn blocks_checked_on_a_tip_connect_while_the_chain_stays_put() {
+    let clock = Arc::new(MockClock::new(GENESIS_TIMESTAMP));
+    let mut blockchain = blockchain(&clock);
+    let miner = key();
+    for _ in 0..2 * MEDIAN_TIME_SPAN {
+        clock.advance(TIME_BASE);
+        let block = mine(&blockchain, blockchain.generate_block(miner));
+        blockchain.add_block(block).unwrap();
+    }
+
+    let mut tip = blockchain.tip();
+    assert_eq!(tip.blocks.len(), MEDIAN_TIME_SPAN);
+    clock.advance(TIME_BASE);
+    let block = mine(&blockchain, blockchain.generate_block(miner));
+    let mut expected = blockchain.clone();
+    expected.add_block(block.clone()).unwrap();
+    tip.add_block(block).unwrap();
+
+    // another block got there first
+    let mut moved_on = blockchain.clone();
+    let other = mine(&moved_on, moved_on.generate_block(key()));
+    moved_on.add_block(other).unwrap();
+    assert!(!moved_on.clone().connect_tip(tip.clone()));
+
+    assert!(blockchain.connect_tip(tip));
+    assert_eq!(blockchain.blocks.len(), expected.blocks.len());
+    assert_eq!(blockchain.weight, expected.weight);
+    assert_eq!(blockchain.cur_dif, expected.cur_dif);
+    assert_eq!(blockchain.balances, expected.balances);
+}
+
+#[test]
+fn received_chains_have_to_share_our_genesis_and_outweigh_us() {
+    let clock = Arc::new(MockClock::new(GENESIS_TIMESTAMP));
+    let mut blockchain = blockchain(&clock);
+    for _ in 0..3 {
+        clock.advance(1);
+        let block = mine(&blockchain, blockchain.generate_block(key()));
+        blockchain.add_block(block).unwrap();
+    }
+
+    let other_network = Blockchain::new(&ChainSpec::new("other"), clock.clone());
+    assert_eq!(
+        blockchain.validate_chain(other_network.blocks).err(),
+        Some(BlockValidationError::GenesisMismatch)
+    );
+
+    let same = blockchain
+        .validate_chain(blockchain.blocks.clone())
+        .unwrap();
+    assert!(!blockchain.prefers(&same));
+    assert!(!blockchain
+        .genesis_chain()
+        .prefers(&blockchain.genesis_chain()));
+    assert!(blockchain.genesis_chain().prefers(&blockchain));
+}
@@ -0,0 +1,99 @@
+use blockchain_p2p::config::CONFIG_FILE;
+use blockchain_p2p::*;
+use std::fs;
+use std::path::PathBuf;
+
+fn data_dir(name: &str) -> PathBuf {
+    let data_dir =
+        std::env::temp_dir().join(format!("blockchain-config-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&data_dir);
+    fs::create_dir_all(&data_dir).unwrap();
+    data_dir
+}
+
+#[test]
+fn settings_left_out_of_the_file_keep_their_defaults() {
+    let data_dir = data_dir("defaults");
+    fs::write(
+        data_dir.join(CONFIG_FILE),
+        r#"
+network = "testnet"
+bootstrap = ["/ip4/127.0.0.1/tcp/4000"]
+mining = false
+mining_threads = 2
+rpc = "127.0.0.1:19545"
+"#,
+    )
+    .unwrap();
+
+    let config = Config::load_from_data_dir(&data_dir).unwrap();
+    assert_eq!(config.data_dir, data_dir);
+    assert_eq!(config.network, "testnet");
+    assert_eq!(config.rpc, "127.0.0.1:19545".parse().unwrap());
+    assert_eq!(
+        config.mining_config(),
+        MiningConfig {
+            enabled: false,
+            threads: 2
+        }
+    );
+    assert_eq!(config.metrics, Config::default().metrics);
+    assert_eq!(config.log_dir(), data_dir);
+
+    let network_config = config.network_config();
+    assert_eq!(network_config.chain.chain_id, "testnet");
+    assert_eq!(
+        network_config.bootstrap_peers,
+        vec!["/ip4/127.0.0.1/tcp/4000".parse().unwrap()]
+    );
+    assert_eq!(network_config.data_dir, Some(data_dir.clone()));
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[test]
+fn a_data_dir_without_a_config_file_uses_the_defaults() {
+    let data_dir = data_dir("empty");
+    let config = Config::load_from_data_dir(&data_dir).unwrap();
+    assert_eq!(
+        config,
+        Config {
+            data_dir: data_dir.clone(),
+            ..Default::default()
+        }
+    );
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[test]
+fn mistakes_in_the_file_are_errors() {
+    let data_dir = data_dir("mistakes");
+    let path = data_dir.join(CONFIG_FILE);
+
+    fs::write(&path, "minning = false").unwrap();
+    assert!(Config::load(&path).is_err());
+
+    fs::write(&path, r#"listen = ["not an address"]"#).unwrap();
+    assert!(Config::load(&path).is_err());
+
+    fs::write(&path, r#"reward_address = "abcd""#).unwrap();
+    assert!(Config::load(&path).unwrap().reward_address().is_err());
+    fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[test]
+fn the_wallet_key_is_kept_in_the_data_dir() {
+    let data_dir = data_dir("wallet");
+    let key_pair = storage::load_wallet_key(&data_dir).unwrap();
+    assert_eq!(
+        storage::load_wallet_key(&data_dir).unwrap().to_bytes(),
+        key_pair.to_bytes()
+    );
+
+    let other_data_dir = self::data_dir("other-wallet");
+    assert_ne!(
+        storage::load_wallet_key(&other_data_dir).unwrap().public,
+        key_pair.public
+    );
+    fs::remove_dir_all(data_dir).unwrap();
+    fs::remove_dir_all(other_data_dir).unwrap();
+}
@@ -0,0 +1,86 @@
+use blockchain_p2p::*;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use tui::backend::TestBackend;
+use tui::Terminal;
+
+async fn start(port: u64) -> (Arc<Node>, Arc<Client>) {
+    let config = NetworkConfig {
+        transport: TransportKind::Memory,
+        chain: ChainSpec::new("test"),
+        listen_addresses: vec![format!("/memory/{}", port).parse().unwrap()],
+        ..Default::default()
+    };
+    let key_pair = Keypair::generate(&mut rand::rngs::OsRng {});
+    let node = Node::start(
+        config.topic("blockchain"),
+        config.topic("transactions"),
+        key_pair.public,
+        MiningConfig::new(false),
+        Arc::new(SystemClock),
+        &config,
+    )
+    .await
+    .unwrap();
+    let node = Arc::new(node);
+    let wallet = Arc::new(Client::new(key_pair, &node));
+    (node, wallet)
+}
+
+fn press(dashboard: &mut Dashboard, code: KeyCode) -> bool {
+    dashboard.handle_key(KeyEvent::new(code, KeyModifiers::NONE))
+}
+
+fn type_in(dashboard: &mut Dashboard, text: &str) {
+    for c in text.chars() {
+        press(dashboard, KeyCode::Char(c));
+    }
+    press(dashboard, KeyCode::Enter);
+}
+
+fn screen(dashboard: &Dashboard) -> String {
+    let mut terminal = Terminal::new(TestBackend::new(120, 40)).unwrap();
+    dashboard.draw(&mut terminal).unwrap();
+    let buffer = terminal.backend().buffer();
+    buffer
+        .content
+        .iter()
+        .map(|cell| cell.symbol.as_str())
+        .collect()
+}
+
+#[async_std::test]
+async fn the_dashboard_shows_the_node_and_acts_on_keys() {
+    let (node, wallet) = start(8001).await;
+    let mut dashboard = Dashboard::new(node.clone(), wallet.clone());
+
+    let tip = node.active_blockchain.lock().unwrap().blocks[0].hash();
+    let shown = screen(&dashboard);
+    assert!(shown.contains("Height 0"));
+    assert!(shown.contains(&format!("Tip {:x}", tip)));
+    assert!(shown.contains(&hex::encode(wallet.key_pair.public)));
+    assert!(shown.contains("Mining off"));
+
+    assert!(press(&mut dashboard, KeyCode::Char('m')));
+    assert!(node.is_mining());
+    assert!(screen(&dashboard).contains("Mining on"));
+    press(&mut dashboard, KeyCode::Char('m'));
+    assert!(!node.is_mining());
+
+    press(&mut dashboard, KeyCode::Char('s'));
+    assert!(screen(&dashboard).contains("Payee address: _"));
+    type_in(&mut dashboard, "not an address");
+    assert!(screen(&dashboard).contains("Wrong payee address format!"));
+
+    let payee = hex::encode(Keypair::generate(&mut rand::rngs::OsRng {}).public);
+    press(&mut dashboard, KeyCode::Char('s'));
+    type_in(&mut dashboard, &payee);
+    type_in(&mut dashboard, "5");
+    assert!(screen(&dashboard).contains("You don't have that many coins!"));
+
+    press(&mut dashboard, KeyCode::Char('a'));
+    press(&mut dashboard, KeyCode::Char('x'));
+    press(&mut dashboard, KeyCode::Esc);
+    assert!(!screen(&dashboard).contains("Peer address"));
+
+    assert!(!press(&mut dashboard, KeyCode::Char('q')));
+}
@@ -0,0 +1,104 @@
+use blockchain_p2p::*;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+async fn start_node(port: u64, mining: bool) -> Arc<Node> {
+    let config = NetworkConfig {
+        transport: TransportKind::Memory,
+        chain: ChainSpec::new("test"),
+        listen_addresses: vec![address(port)],
+        ..Default::default()
+    };
+    let key_pair = Keypair::generate(&mut rand::rngs::OsRng {});
+    let node = Node::start(
+        config.topic("blockchain"),
+        config.topic("transactions"),
+        key_pair.public,
+        MiningConfig::new(mining),
+        Arc::new(SystemClock),
+        &config,
+    )
+    .await
+    .unwrap();
+    Arc::new(node)
+}
+
+fn address(port: u64) -> Multiaddr {
+    format!("/memory/{}", port).parse().unwrap()
+}
+
+fn scrape(server: &MetricsServer) -> String {
+    let address = server.local_addr().unwrap();
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(
+        stream,
+        "GET /metrics HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        address
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response.split_once("\r\n\r\n").unwrap().1.to_string()
+}
+
+// The value of an unlabelled metric
+fn metric(server: &MetricsServer, name: &str) -> u64 {
+    let metrics = scrape(server);
+    let line = metrics
+        .lines()
+        .find(|line| line.split(' ').next() == Some(name))
+        .unwrap_or_else(|| panic!("no {} in\n{}", name, metrics));
+    line.split(' ').nth(1).unwrap().parse().unwrap()
+}
+
+// Sleeps on the runtime rather than the thread, so the node keeps running meanwhile
+async fn wait_until(condition: impl Fn() -> bool) {
+    let start = Instant::now();
+    while !condition() {
+        assert!(start.elapsed() < TIMEOUT, "timed out");
+        task::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+// A sealed block isn't taken while mining is off, one the node already took is added under
+// its locks, so once they're free the tip stays put
+fn stop_mining(node: &Node) {
+    node.set_mining(false);
+    let _block = node.active_block.lock().unwrap();
+    let _blockchain = node.active_blockchain.lock().unwrap();
+}
+
+fn weight(node: &Node) -> u32 {
+    node.active_blockchain.lock().unwrap().weight
+}
+
+#[async_std::test]
+async fn mining_and_reorgs_show_up_in_the_metrics() {
+    let first = start_node(7001, false).await;
+    let second = start_node(7002, true).await;
+    let server = MetricsServer::start("127.0.0.1:0".parse().unwrap(), second.clone()).unwrap();
+
+    wait_until(|| metric(&server, "blockchain_height") >= 2).await;
+    assert_eq!(metric(&server, "blockchain_peers"), 0);
+    assert!(metric(&server, "blockchain_hashes_total") > 0);
+    assert_eq!(metric(&server, "blockchain_reorgs_total"), 0);
+
+    // the same forks as in forks_resolve_to_the_heavier_chain
+    stop_mining(&second);
+    first.set_mining(true);
+    wait_until(|| weight(&first) > weight(&second)).await;
+    stop_mining(&first);
+
+    second.add_peer(address(7001));
+    wait_until(|| metric(&server, "blockchain_weight") == weight(&first) as u64).await;
+    assert_eq!(metric(&server, "blockchain_reorgs_total"), 1);
+    assert!(metric(&server, "blockchain_blocks_disconnected_total") >= 2);
+    assert_eq!(metric(&server, "blockchain_peers"), 1);
+    assert_eq!(metric(&server, "blockchain_mempool_transactions"), 0);
+
+    first.shutdown();
+    wait_until(|| metric(&server, "blockchain_peers") == 0).await;
+}
@@ -0,0 +1,340 @@
+use blockchain_p2p::p2p::OutEvent;
+use blockchain_p2p::sync;
+use blockchain_p2p::*;
+use libp2p::request_response::{RequestResponseEvent, RequestResponseMessage};
+use std::time::{Duration, Instant};
+
+// Generous, the nodes share the machine with every other test
+const TIMEOUT: Duration = Duration::from_secs(180);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Memory transport ports are shared by the whole test process, so every test uses its own
+fn config(port: u64, bootstrap_peers: &[u64]) -> NetworkConfig {
+    NetworkConfig {
+        transport: TransportKind::Memory,
+        chain: ChainSpec::new("test"),
+        listen_addresses: vec![address(port)],
+        bootstrap_peers: bootstrap_peers.iter().map(|p| address(*p)).collect(),
+        ..Default::default()
+    }
+}
+
+fn address(port: u64) -> Multiaddr {
+    format!("/memory/{}", port).parse().unwrap()
+}
+
+async fn start_node(port: u64, bootstrap_peers: &[u64], mining: bool) -> (Node, Keypair) {
+    let config = config(port, bootstrap_peers);
+    let key_pair = Keypair::generate(&mut rand::rngs::OsRng {});
+    let node = Node::start(
+        config.topic("blockchain"),
+        config.topic("transactions"),
+        key_pair.public,
+        MiningConfig::new(mining),
+        Arc::new(SystemClock),
+        &config,
+    )
+    .await
+    .unwrap();
+    (node, key_pair)
+}
+
+// Sleeps on the runtime rather than the thread, so the nodes keep running meanwhile
+async fn wait_until(mut condition: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !condition() {
+        assert!(start.elapsed() < TIMEOUT, "timed out");
+        task::sleep(POLL_INTERVAL).await;
+    }
+}
+
+// A sealed block isn't taken while mining is off, one the node already took is added under
+// its locks, so once they're free the tip stays put
+fn stop_mining(node: &Node) {
+    node.set_mining(false);
+    let _block = node.active_block.lock().unwrap();
+    let _blockchain = node.active_blockchain.lock().unwrap();
+}
+
+fn height(node: &Node) -> usize {
+    node.active_blockchain.lock().unwrap().blocks.len()
+}
+
+fn weight(node: &Node) -> u32 {
+    node.active_blockchain.lock().unwrap().weight
+}
+
+fn tip(node: &Node) -> Option<Hash> {
+    let blockchain = node.active_blockchain.lock().unwrap();
+    blockchain.blocks.last().map(|b| b.hash())
+}
+
+fn balance(node: &Node, key: &PublicKey) -> u64 {
+    let blockchain = node.active_blockchain.lock().unwrap();
+    *blockchain.balances.get(key.as_bytes()).unwrap_or(&0)
+}
+
+fn has_transaction(node: &Node, from: &PublicKey, amount: u64) -> bool {
+    let blockchain = node.active_blockchain.lock().unwrap();
+    blockchain
+        .blocks
+        .iter()
+        .flat_map(|b| &b.transactions)
+        .any(|t| &t.data.from == from && t.data.amount == amount)
+}
+
+#[async_std::test]
+async fn mined_blocks_reach_peers() {
+    let (miner, _) = start_node(1001, &[], true).await;
+    let (peer, _) = start_node(1002, &[1001], false).await;
+
+    wait_until(|| height(&peer) >= 4).await;
+    assert_eq!(peer.connected_peers().len(), 1);
+
+    let peer_blockchain = peer.active_blockchain.lock().unwrap();
+    let miner_blockchain = miner.active_blockchain.lock().unwrap();
+    for (ours, theirs) in peer_blockchain.blocks.iter().zip(&miner_blockchain.blocks) {
+        assert_eq!(ours.hash(), theirs.hash());
+    }
+}
+
+#[async_std::test]
+async fn transactions_reach_the_miner() {
+    let (miner, miner_key) = start_node(2001, &[], false).await;
+    let (sender, sender_key) = start_node(2002, &[2001], true).await;
+
+    // the sender mines its own coins, then hands mining over once the miner knows about them
+    wait_until(|| balance(&miner, &sender_key.public) >= MINING_REW).await;
+    stop_mining(&sender);
+    wait_until(|| tip(&miner) == tip(&sender)).await;
+    miner.set_mining(true);
+
+    let wallet = Client::new(sender_key, &sender);
+    wallet.send_transaction(miner_key.public, 42).unwrap();
+
+    wait_until(|| has_transaction(&miner, &wallet.key_pair.public, 42)).await;
+    wait_until(|| has_transaction(&sender, &wallet.key_pair.public, 42)).await;
+}
+
+#[async_std::test]
+async fn peers_are_discovered_past_the_bootstrap_peers() {
+    // each node only knows the one before it
+    let (first, _) = start_node(8001, &[], false).await;
+    let (second, _) = start_node(8002, &[8001], false).await;
+    let (third, _) = start_node(8003, &[8002], false).await;
+    let (fourth, _) = start_node(8004, &[8003], false).await;
+
+    let connected = |node: &Node, peer: &Node| {
+        node.connected_peers()
+            .iter()
+            .any(|(peer_id, _)| *peer_id == peer.peer_id)
+    };
+    wait_until(|| connected(&first, &fourth) && connected(&fourth, &first)).await;
+    assert!(connected(&second, &third));
+}
+
+// A bare network stack for sending arbitrary gossip, its swarm only runs while it's driven
+async fn start_raw_peer(port: u64, bootstrap_peers: &[u64]) -> NetworkManager {
+    // not subscribed, so nothing gets forwarded to it
+    NetworkManager::start(vec![], &config(port, bootstrap_peers))
+        .await
+        .unwrap()
+}
+
+fn publish(peer: &mut NetworkManager, topic: &str, message: NetworkMessage) {
+    let topic = config(0, &[]).topic(topic);
+    peer.swarm
+        .behaviour_mut()
+        .gossipsub
+        .publish(topic, message.encode())
+        .unwrap();
+}
+
+// wait_until, driving the raw peers meanwhile
+async fn drive_until(
+    peers: &mut [&mut NetworkManager],
+    mut condition: impl FnMut(&[&mut NetworkManager]) -> bool,
+) {
+    let start = Instant::now();
+    while !condition(peers) {
+        assert!(start.elapsed() < TIMEOUT, "timed out");
+        for peer in peers.iter_mut() {
+            if let Ok(event) =
+                async_std::future::timeout(POLL_INTERVAL, peer.swarm.select_next_some()).await
+            {
+                // answered like a node that has only the genesis block
+                if let Some(SwarmEvent::Behaviour(OutEvent::Sync(
+                    RequestResponseEvent::Message {
+                        message:
+                            RequestResponseMessage::Request {
+                                request, channel, ..
+                            },
+                        ..
+                    },
+                ))) = peer.handle_event(event)
+                {
+                    let genesis = Blockchain::new(&ChainSpec::new("test"), Arc::new(SystemClock));
+                    let response = sync::respond(&genesis, request);
+                    let _ = peer
+                        .swarm
+                        .behaviour_mut()
+                        .sync
+                        .send_response(channel, response);
+                }
+            }
+        }
+    }
+}
+
+// gossip can only be published to the node once its subscriptions are known
+fn knows_subscriptions(peer: &NetworkManager, node: &Node) -> bool {
+    let gossipsub = &peer.swarm.behaviour().gossipsub;
+    gossipsub
+        .all_peers()
+        .any(|(peer_id, topics)| *peer_id == node.peer_id && topics.len() == 2)
+}
+
+fn score(node: &Node, peer: &PeerId) -> Option<i32> {
+    node.connected_peers()
+        .into_iter()
+        .find(|(peer_id, _)| peer_id == peer)
+        .map(|(_, score)| score)
+}
+
+#[async_std::test]
+async fn invalid_and_duplicate_messages_are_not_relayed() {
+    let (relay, key_pair) = start_node(9001, &[], false).await;
+    let (receiver, _) = start_node(9002, &[9001], false).await;
+    wait_until(|| !relay.connected_peers().is_empty()).await;
+    relay.set_mining(true);
+    wait_until(|| balance(&relay, &key_pair.public) >= 42).await;
+    stop_mining(&relay);
+    wait_until(|| tip(&receiver) == tip(&relay)).await;
+
+    let mut sender = start_raw_peer(9003, &[9001]).await;
+    let mut replayer = start_raw_peer(9004, &[9001]).await;
+    let replayer_id = *replayer.swarm.local_peer_id();
+    drive_until(&mut [&mut sender, &mut replayer], |peers| {
+        peers.iter().all(|peer| knows_subscriptions(peer, &relay))
+    })
+    .await;
+
+    let recipient = Keypair::generate(&mut rand::rngs::OsRng {}).public;
+    let transaction = Transaction::new(recipient, 42, &key_pair);
+    let hash = transaction.hash();
+    publish(
+        &mut sender,
+        "transactions",
+        NetworkMessage::Transaction(Box::new(transaction.clone())),
+    );
+    drive_until(&mut [&mut sender, &mut replayer], |_| {
+        receiver.mempool.lock().unwrap().contains(&hash)
+    })
+    .await;
+
+    // the same content from another peer is the same message, then two invalid ones
+    publish(
+        &mut replayer,
+        "transactions",
+        NetworkMessage::Transaction(Box::new(transaction.clone())),
+    );
+    let mut forged = Transaction::new(recipient, 1, &key_pair);
+    forged.data.amount = 1000;
+    publish(
+        &mut replayer,
+        "transactions",
+        NetworkMessage::Transaction(Box::new(forged)),
+    );
+    let mut blocks = relay.active_blockchain.lock().unwrap().blocks.clone();
+    blocks[1].header.transactions_root = Default::default();
+    publish(&mut replayer, "blockchain", NetworkMessage::Blocks(blocks));
+
+    drive_until(&mut [&mut sender, &mut replayer], |_| {
+        relay
+            .banned_peers()
+            .iter()
+            .any(|(peer, _)| *peer == replayer_id)
+    })
+    .await;
+    // the replayed transaction never reached validation
+    assert_eq!(relay.metrics.messages_received.get(), 3);
+
+    // whatever the relay had forwarded would arrive before its next block
+    let relayed_height = height(&relay);
+    relay.set_mining(true);
+    drive_until(&mut [&mut sender], |_| height(&receiver) > relayed_height).await;
+    assert_eq!(score(&receiver, &relay.peer_id), Some(0));
+}
+
+#[async_std::test]
+async fn forks_resolve_to_the_heavier_chain() {
+    let (first, _) = start_node(3001, &[], false).await;
+    let (second, _) = start_node(3002, &[], true).await;
+
+    // two unconnected forks, the first one heavier
+    wait_until(|| height(&second) >= 3).await;
+    stop_mining(&second);
+    first.set_mining(true);
+    wait_until(|| weight(&first) > weight(&second)).await;
+    stop_mining(&first);
+
+    let heaviest = tip(&first);
+    second.add_peer(address(3001));
+
+    wait_until(|| tip(&second) == heaviest).await;
+    assert_eq!(tip(&first), heaviest);
+}
+
+#[async_std::test]
+async fn shutting_down_saves_the_chain_and_bans_and_tells_peers() {
+    let data_dir = std::env::temp_dir().join(format!("blockchain-test-{}", std::process::id()));
+    let node_config = NetworkConfig {
+        data_dir: Some(data_dir.clone()),
+        ..config(6001, &[])
+    };
+    let key_pair = Keypair::generate(&mut rand::rngs::OsRng {});
+    let start = |mining| {
+        Node::start(
+            node_config.topic("blockchain"),
+            node_config.topic("transactions"),
+            key_pair.public,
+            MiningConfig::new(mining),
+            Arc::new(SystemClock),
+            &node_config,
+        )
+    };
+    let node = start(true).await.unwrap();
+    let (peer, _) = start_node(6002, &[6001], false).await;
+    let mut events = peer.events.subscribe();
+
+    let wallet = Client::new(Keypair::from_bytes(&key_pair.to_bytes()).unwrap(), &node);
+    wait_until(|| balance(&node, &key_pair.public) >= 42).await;
+    stop_mining(&node);
+    let hash = wallet
+        .send_transaction(Keypair::generate(&mut rand::rngs::OsRng {}).public, 42)
+        .unwrap();
+    wait_until(|| node.mempool.lock().unwrap().contains(&hash)).await;
+    let saved_tip = tip(&node);
+    let banned = PeerId::random();
+    node.ban_peer(banned, None);
+    wait_until(|| !node.banned_peers().is_empty()).await;
+    let saved_bans = node.banned_peers();
+
+    node.shutdown();
+    wait_until(|| {
+        std::iter::from_fn(|| events.try_recv().ok())
+            .any(|event| matches!(event, Event::PeerDisconnected(_)))
+    })
+    .await;
+    drop(node);
+
+    // the port is free again once the node is gone
+    let node = start(false).await.unwrap();
+    assert_eq!(tip(&node), saved_tip);
+    assert!(node.mempool.lock().unwrap().contains(&hash));
+    assert_eq!(node.banned_peers(), saved_bans);
+    assert_eq!(saved_bans[0].0, banned);
+
+    node.shutdown();
+    let _ = std::fs::remove_dir_all(data_dir);
+}
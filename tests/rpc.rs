@@ -0,0 +1,212 @@
+use blockchain_p2p::*;
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+async fn start(port: u64) -> (RpcServer, Arc<Node>) {
+    let config = NetworkConfig {
+        transport: TransportKind::Memory,
+        chain: ChainSpec::new("test"),
+        listen_addresses: vec![format!("/memory/{}", port).parse().unwrap()],
+        ..Default::default()
+    };
+    let key_pair = Keypair::generate(&mut rand::rngs::OsRng {});
+    let node = Node::start(
+        config.topic("blockchain"),
+        config.topic("transactions"),
+        key_pair.public,
+        MiningConfig::new(false),
+        Arc::new(SystemClock),
+        &config,
+    )
+    .await
+    .unwrap();
+    let node = Arc::new(node);
+    let wallet = Arc::new(Client::new(key_pair, &node));
+
+    let server = RpcServer::start("127.0.0.1:0".parse().unwrap(), node.clone(), wallet).unwrap();
+    (server, node)
+}
+
+// A bare HTTP/1.1 request, so the tests don't depend on a client library
+fn post(address: SocketAddr, body: &Value) -> Value {
+    let body = body.to_string();
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(
+        stream,
+        "POST / HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        address,
+        body.len(),
+        body
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    serde_json::from_str(body).unwrap()
+}
+
+fn call(server: &RpcServer, method: &str, params: Value) -> Value {
+    let request = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 });
+    let response = post(server.local_addr().unwrap(), &request);
+    assert_eq!(response["id"], 1);
+    response
+}
+
+fn result(server: &RpcServer, method: &str, params: Value) -> Value {
+    let response = call(server, method, params);
+    assert!(response.get("error").is_none(), "{}", response);
+    response["result"].clone()
+}
+
+// Sleeps on the runtime rather than the thread, so the node keeps running meanwhile
+async fn wait_until(condition: impl Fn() -> bool) {
+    let start = Instant::now();
+    while !condition() {
+        assert!(start.elapsed() < TIMEOUT, "timed out");
+        task::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[async_std::test]
+async fn blocks_can_be_looked_up_by_height_and_hash() {
+    let (server, _node) = start(4001).await;
+
+    let info = result(&server, "chain_info", Value::Null);
+    assert_eq!(info["height"], 0);
+    assert_eq!(info["tip"], info["genesis"]);
+
+    let genesis = result(&server, "block_by_height", json!([0]));
+    assert_eq!(genesis["hash"], info["genesis"]);
+    assert_eq!(
+        result(&server, "block_by_hash", json!([info["genesis"]])),
+        genesis
+    );
+    assert_eq!(result(&server, "block_by_height", json!([1])), Value::Null);
+}
+
+#[async_std::test]
+async fn bad_requests_get_errors() {
+    let (server, _node) = start(4002).await;
+
+    let response = call(&server, "no_such_method", Value::Null);
+    assert_eq!(response["error"]["code"], -32601);
+    let response = call(&server, "balance", json!(["not an address"]));
+    assert_eq!(response["error"]["code"], -32602);
+
+    let payee = hex::encode(Keypair::generate(&mut rand::rngs::OsRng {}).public);
+    let response = call(&server, "send_transaction", json!([payee, 1]));
+    assert_eq!(response["error"]["code"], -32000);
+
+    let response = post(server.local_addr().unwrap(), &json!("{"));
+    assert_eq!(response["error"]["code"], -32600);
+}
+
+#[async_std::test]
+async fn mining_and_payments_go_through_rpc() {
+    let (server, node) = start(4003).await;
+
+    assert_eq!(result(&server, "mining_status", Value::Null), false);
+    result(&server, "set_mining", json!([true]));
+    assert!(node.is_mining());
+
+    let wallet = result(&server, "wallet_address", Value::Null);
+    let balance = || {
+        result(&server, "balance", json!([wallet]))
+            .as_u64()
+            .unwrap()
+    };
+    wait_until(|| balance() >= 2 * MINING_REW).await;
+
+    // paused, so that new blocks don't push the transaction out of the recent events
+    result(&server, "set_mining", json!([false]));
+    let payee = hex::encode(Keypair::generate(&mut rand::rngs::OsRng {}).public);
+    let hash = result(&server, "send_transaction", json!([payee, 42]));
+    assert!(hash.is_string());
+
+    // what's pending counts against the balance, even if a block mined before the pause lands
+    let other = hex::encode(Keypair::generate(&mut rand::rngs::OsRng {}).public);
+    result(&server, "send_transaction", json!([other, balance() - 42]));
+    let response = call(&server, "send_transaction", json!([other, MINING_REW + 1]));
+    assert_eq!(response["error"]["code"], -32000);
+    assert_eq!(response["error"]["message"], "Insufficient balance");
+    wait_until(|| {
+        let events = result(&server, "recent_events", Value::Null);
+        events
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|e| e["event"] == "tx_received")
+    })
+    .await;
+
+    result(&server, "set_mining", json!([true]));
+    wait_until(|| result(&server, "balance", json!([payee])) == 42).await;
+    let events = result(&server, "recent_events", Value::Null);
+    let events = events.as_array().unwrap();
+    assert!(events.iter().any(|e| e["event"] == "block_connected"));
+}
+
+#[async_std::test]
+async fn the_client_returns_results_and_errors() {
+    let (server, _node) = start(4004).await;
+    let client = RpcClient::new(server.local_addr().unwrap());
+
+    let info = client.call("chain_info", Value::Null).unwrap();
+    assert_eq!(info["height"], 0);
+    assert_eq!(
+        client.call("block_by_height", json!([1])).unwrap(),
+        Value::Null
+    );
+
+    let error = client.call("balance", json!(["xyz"])).unwrap_err();
+    let error = error.downcast::<rpc::RpcError>().unwrap();
+    assert_eq!(error.code, -32602);
+    assert_eq!(error.message, "Invalid address");
+}
+
+#[async_std::test]
+async fn peers_can_be_banned_and_unbanned() {
+    let (server, _node) = start(4005).await;
+    assert_eq!(result(&server, "peers", Value::Null), json!([]));
+
+    let peer = PeerId::random().to_string();
+    assert_eq!(result(&server, "ban_peer", json!([peer, 60])), true);
+    wait_until(|| result(&server, "banned_peers", Value::Null)[0]["peer"] == peer).await;
+    let banned = result(&server, "banned_peers", Value::Null);
+    assert!(banned[0]["expires_in"].as_u64() <= Some(60));
+    assert_eq!(banned[0]["reason"], "banned by operator");
+
+    assert_eq!(result(&server, "unban_peer", json!([peer])), true);
+    wait_until(|| result(&server, "banned_peers", Value::Null) == json!([])).await;
+
+    assert_eq!(result(&server, "ban_peer", json!([peer, null])), true);
+    wait_until(|| result(&server, "banned_peers", Value::Null)[0]["expires_in"].is_null()).await;
+
+    let response = call(&server, "ban_peer", json!(["not a peer", null]));
+    assert_eq!(response["error"]["code"], -32602);
+}
+
+#[async_std::test]
+async fn peers_can_be_added() {
+    let (server, _node) = start(4006).await;
+    let (_other_server, other) = start(4007).await;
+
+    assert_eq!(result(&server, "add_peer", json!(["/memory/4007"])), true);
+    wait_until(|| {
+        result(&server, "peers", Value::Null)
+            .as_array()
+            .unwrap()
+            .len()
+            == 1
+    })
+    .await;
+    wait_until(|| other.connected_peers().len() == 1).await;
+
+    let response = call(&server, "add_peer", json!(["not an address"]));
+    assert_eq!(response["error"]["code"], -32602);
+}
@@ -0,0 +1,31 @@
+use blockchain_p2p::sim::{simulate, Partition, SimConfig, SimNodeConfig};
+
+fn config() -> SimConfig {
+    SimConfig {
+        nodes: vec![SimNodeConfig { hashrate: 50. }; 3],
+        duration: 1800,
+        seed: 7,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn runs_are_reproducible() {
+    assert_eq!(simulate(config()), simulate(config()));
+}
+
+#[test]
+fn partitioned_nodes_reorg_after_healing() {
+    let report = simulate(SimConfig {
+        partitions: vec![Partition {
+            start: 300,
+            end: 900,
+            isolated: vec![0],
+        }],
+        ..config()
+    });
+
+    assert!(report.reorgs > 0);
+    assert!(report.orphaned > 0);
+    assert!(report.converged);
+}
@@ -0,0 +1,157 @@
+use blockchain_p2p::*;
+use serde_json::{json, Value};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+use tungstenite::{Message, WebSocket};
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+async fn start_node(port: u64, mining: bool) -> (Node, Keypair) {
+    let config = NetworkConfig {
+        transport: TransportKind::Memory,
+        chain: ChainSpec::new("test"),
+        listen_addresses: vec![address(port)],
+        ..Default::default()
+    };
+    let key_pair = Keypair::generate(&mut rand::rngs::OsRng {});
+    let node = Node::start(
+        config.topic("blockchain"),
+        config.topic("transactions"),
+        key_pair.public,
+        MiningConfig::new(mining),
+        Arc::new(SystemClock),
+        &config,
+    )
+    .await
+    .unwrap();
+    (node, key_pair)
+}
+
+fn address(port: u64) -> Multiaddr {
+    format!("/memory/{}", port).parse().unwrap()
+}
+
+fn start_server(node: &Node) -> SubscriptionServer {
+    SubscriptionServer::start("127.0.0.1:0".parse().unwrap(), node.events.clone()).unwrap()
+}
+
+fn subscribe(server: &SubscriptionServer, filter: Value) -> WebSocket<TcpStream> {
+    let stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let (mut socket, _) =
+        tungstenite::client(format!("ws://{}", server.local_addr()), stream).unwrap();
+    socket
+        .write_message(Message::Text(filter.to_string()))
+        .unwrap();
+    assert_eq!(next_event(&mut socket)["event"], "subscribed");
+    socket
+}
+
+fn next_event(socket: &mut WebSocket<TcpStream>) -> Value {
+    loop {
+        if let Message::Text(text) = socket.read_message().unwrap() {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
+
+// Sleeps on the runtime rather than the thread, so the node keeps running meanwhile
+async fn wait_until(condition: impl Fn() -> bool) {
+    let start = Instant::now();
+    while !condition() {
+        assert!(start.elapsed() < TIMEOUT, "timed out");
+        task::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+// A sealed block isn't taken while mining is off, one the node already took is added under
+// its locks, so once they're free the tip stays put
+fn stop_mining(node: &Node) {
+    node.set_mining(false);
+    let _block = node.active_block.lock().unwrap();
+    let _blockchain = node.active_blockchain.lock().unwrap();
+}
+
+fn tip(node: &Node) -> String {
+    let blockchain = node.active_blockchain.lock().unwrap();
+    format!("{:x}", blockchain.blocks.last().unwrap().hash())
+}
+
+fn weight(node: &Node) -> u32 {
+    node.active_blockchain.lock().unwrap().weight
+}
+
+#[async_std::test]
+async fn subscribers_only_hear_about_their_addresses() {
+    let (node, key_pair) = start_node(5001, false).await;
+    let server = start_server(&node);
+    let payer = hex::encode(key_pair.public);
+    let payee = Keypair::generate(&mut rand::rngs::OsRng {}).public;
+
+    let mut payer_socket = subscribe(&server, json!({ "addresses": [payer] }));
+    let mut payee_socket = subscribe(&server, json!({ "addresses": [hex::encode(payee)] }));
+    let mut transaction_socket = subscribe(&server, json!({ "subscribe": ["tx_received"] }));
+
+    node.set_mining(true);
+    let event = next_event(&mut payer_socket);
+    assert_eq!(event["event"], "mining_solved");
+    assert_eq!(event["block"]["mined_by"], payer);
+    let event = next_event(&mut payer_socket);
+    assert_eq!(event["event"], "block_connected");
+    assert_eq!(event["block"]["mined_by"], payer);
+
+    let wallet = Client::new(key_pair, &node);
+    wait_until(|| {
+        let blockchain = node.active_blockchain.lock().unwrap();
+        blockchain.balances.get(wallet.key_pair.public.as_bytes()) >= Some(&42)
+    })
+    .await;
+    let hash = format!("{:x}", wallet.send_transaction(payee, 42).unwrap());
+
+    // none of the blocks before involved the payee
+    let event = next_event(&mut payee_socket);
+    assert_eq!(event["event"], "tx_received");
+    assert_eq!(event["transaction"]["hash"], hash);
+    let event = next_event(&mut transaction_socket);
+    assert_eq!(event["transaction"]["hash"], hash);
+
+    let event = next_event(&mut payee_socket);
+    assert_eq!(event["event"], "mining_solved");
+    let event = next_event(&mut payee_socket);
+    assert_eq!(event["event"], "block_connected");
+    assert_eq!(event["block"]["transactions"][0]["hash"], hash);
+}
+
+#[async_std::test]
+async fn switching_to_a_heavier_fork_is_a_reorg() {
+    let (first, _) = start_node(5011, false).await;
+    let (second, _) = start_node(5012, true).await;
+    let server = start_server(&second);
+    let mut socket = subscribe(
+        &server,
+        json!({ "subscribe": ["block_disconnected", "tip_changed"] }),
+    );
+
+    // the same forks as in forks_resolve_to_the_heavier_chain
+    wait_until(|| second.active_blockchain.lock().unwrap().blocks.len() >= 3).await;
+    stop_mining(&second);
+    let abandoned = tip(&second);
+    first.set_mining(true);
+    wait_until(|| weight(&first) > weight(&second)).await;
+    stop_mining(&first);
+
+    let heaviest = tip(&first);
+    second.add_peer(address(5011));
+
+    // the tips up to here were second's own blocks
+    let mut event = next_event(&mut socket);
+    while event["event"] == "tip_changed" {
+        event = next_event(&mut socket);
+    }
+    assert_eq!(event["event"], "block_disconnected");
+    assert_eq!(event["block"]["hash"], abandoned);
+    while event["event"] != "tip_changed" {
+        event = next_event(&mut socket);
+    }
+    assert_eq!(event["hash"], heaviest);
+}